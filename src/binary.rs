@@ -0,0 +1,214 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use memmap2::{MmapMut, MmapOptions};
+use ndarray::prelude::*;
+
+/// First bytes of every binary vectors file.
+pub const MAGIC: &[u8; 8] = b"CLUSTVEC";
+const VERSION: u32 = 1;
+/// Fixed part of the header, magic, version, dtype, count, dimension and model name length.
+const FIXED_HEADER_LEN: usize = 8 + 4 + 4 + 8 + 8 + 4;
+/// Rows start on a multiple of this, so they can be read as f32 straight from the mapping.
+const DATA_ALIGN: usize = 16;
+
+/// Element type of the rows, only f32 for now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dtype {
+    F32 = 0,
+}
+
+/// Everything before the rows of a binary vectors file.
+///
+/// All integers are little-endian, the model name is utf-8, and the header is zero padded to a
+/// multiple of 16 bytes. Then come `count` rows of `dimension` little-endian values.
+///
+/// | bytes | field                                  |
+/// |-------|----------------------------------------|
+/// | 8     | magic, `CLUSTVEC`                      |
+/// | 4     | version, 1                             |
+/// | 4     | dtype, 0 for f32                       |
+/// | 8     | count                                  |
+/// | 8     | dimension                              |
+/// | 4     | model name length, then the model name |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub dtype: Dtype,
+    pub count: usize,
+    pub dimension: usize,
+    pub model: String,
+}
+
+impl Header {
+    fn data_offset(&self) -> usize {
+        (FIXED_HEADER_LEN + self.model.len()).div_ceil(DATA_ALIGN) * DATA_ALIGN
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.data_offset());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.dtype as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.count as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.dimension as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.model.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(self.model.as_bytes());
+        bytes.resize(self.data_offset(), 0);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<Header> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+
+        if bytes.len() < FIXED_HEADER_LEN || bytes[..8] != MAGIC[..] {
+            return Err(invalid("not a binary vectors file"));
+        }
+        if u32_at(8) != VERSION {
+            return Err(invalid(&format!("unsupported binary vectors version {}", u32_at(8))));
+        }
+        let dtype = match u32_at(12) {
+            0 => Dtype::F32,
+            other => return Err(invalid(&format!("unsupported dtype {}", other))),
+        };
+        let model_len = u32_at(32) as usize;
+        let model = bytes
+            .get(FIXED_HEADER_LEN..FIXED_HEADER_LEN + model_len)
+            .ok_or_else(|| invalid("truncated header"))?;
+        let model = String::from_utf8(model.to_vec()).map_err(|_| invalid("model name isn't utf-8"))?;
+
+        Ok(Header { dtype, count: u64_at(16) as usize, dimension: u64_at(24) as usize, model })
+    }
+}
+
+/// A binary vectors file mapped into memory.
+///
+/// The mapping is copy-on-write, so rows can be normalized in place without touching the file.
+pub struct VectorFile {
+    header: Header,
+    mmap: MmapMut,
+}
+
+impl VectorFile {
+    pub fn open(filename: &str) -> io::Result<VectorFile> {
+        if cfg!(target_endian = "big") {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "binary vectors need a little-endian machine"));
+        }
+
+        let file = File::open(filename)?;
+        // safety: the mapping is private, other processes changing the file is the only risk,
+        // the same as for any memory mapped file
+        let mmap = unsafe { MmapOptions::new().map_copy(&file)? };
+        let header = Header::from_bytes(&mmap)?;
+
+        let expected = header
+            .count
+            .checked_mul(header.dimension)
+            .and_then(|values| values.checked_mul(std::mem::size_of::<f32>()))
+            .and_then(|bytes| bytes.checked_add(header.data_offset()))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} vectors of dimension {} don't fit in memory", header.count, header.dimension),
+                )
+            })?;
+        if mmap.len() != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected {} bytes for {} vectors of dimension {}, found {}", expected, header.count, header.dimension, mmap.len()),
+            ));
+        }
+
+        Ok(VectorFile { header, mmap })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn view(&self) -> ArrayView2<'_, f32> {
+        let shape = (self.header.count, self.header.dimension);
+        ArrayView2::from_shape(shape, self.rows()).expect("length checked on open")
+    }
+
+    pub fn view_mut(&mut self) -> ArrayViewMut2<'_, f32> {
+        let shape = (self.header.count, self.header.dimension);
+        ArrayViewMut2::from_shape(shape, self.rows_mut()).expect("length checked on open")
+    }
+
+    fn rows(&self) -> &[f32] {
+        // safety: every bit pattern is a valid f32, and the data offset is aligned
+        let (prefix, rows, _) = unsafe { self.mmap[self.header.data_offset()..].align_to::<f32>() };
+        assert!(prefix.is_empty(), "mapping is page aligned");
+        rows
+    }
+
+    fn rows_mut(&mut self) -> &mut [f32] {
+        let offset = self.header.data_offset();
+        // safety: as for rows
+        let (prefix, rows, _) = unsafe { self.mmap[offset..].align_to_mut::<f32>() };
+        assert!(prefix.is_empty(), "mapping is page aligned");
+        rows
+    }
+}
+
+/// Writes embeddings as a binary vectors file, tagged with the model that made them.
+pub fn write(filename: &str, embeddings: ArrayView2<f32>, model: &str) -> io::Result<()> {
+    let header = Header {
+        dtype: Dtype::F32,
+        count: embeddings.nrows(),
+        dimension: embeddings.ncols(),
+        model: model.to_string(),
+    };
+
+    let mut writer = BufWriter::new(File::create(filename)?);
+    writer.write_all(&header.to_bytes())?;
+    for value in embeddings.iter() {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    writer.flush()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_round_trips() {
+        let header = Header { dtype: Dtype::F32, count: 3, dimension: 384, model: "all-MiniLM-L6-v2".to_string() };
+        let bytes = header.to_bytes();
+        assert_eq!(0, bytes.len() % DATA_ALIGN);
+        assert_eq!(header, Header::from_bytes(&bytes).unwrap());
+
+        assert!(Header::from_bytes(b"[[0.1, 0.2]]").is_err());
+        assert!(Header::from_bytes(&bytes[..FIXED_HEADER_LEN + 3]).is_err());
+    }
+
+    #[test]
+    fn test_it_can_write_and_map_vectors() {
+        let filename = std::env::temp_dir().join(format!("cluster-binary-test-{}.vec", std::process::id()));
+        let filename = filename.to_str().unwrap();
+        let embeddings = array![[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]];
+
+        write(filename, embeddings.view(), "model").unwrap();
+
+        let mut vectors = VectorFile::open(filename).unwrap();
+        assert_eq!("model", vectors.header().model);
+        assert_eq!(embeddings.view(), vectors.view());
+
+        // changes stay in memory
+        vectors.view_mut()[[0, 0]] = 10.0;
+        assert_eq!(10.0, vectors.view()[[0, 0]]);
+        assert_eq!(embeddings.view(), VectorFile::open(filename).unwrap().view());
+
+        // a header whose size doesn't fit in memory is rejected, not wrapped around
+        let header = Header { dtype: Dtype::F32, count: usize::MAX / 2, dimension: 3, model: "model".to_string() };
+        std::fs::write(filename, header.to_bytes()).unwrap();
+        let error = VectorFile::open(filename).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        std::fs::remove_file(filename).unwrap();
+    }
+}
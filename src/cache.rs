@@ -0,0 +1,642 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use memmap2::Mmap;
+use sha2::{Digest, Sha256};
+
+use crate::cluster::Embedding;
+use crate::embed::Embedder;
+use crate::error::{Error, Result};
+
+/// Environment variable the CLI reads the cache directory from, when there's no `--cache-dir`.
+pub const CACHE_DIR_ENV: &str = "CLUSTER_CACHE_DIR";
+/// First bytes of every cache file.
+const MAGIC: &[u8; 8] = b"CLUSTEMB";
+const VERSION: u32 = 1;
+const EXTENSION: &str = "embeddings";
+
+/// sha256 of a text with its whitespace normalized.
+pub type Key = [u8; 32];
+
+/// Texts that only differ in whitespace get the same key, tokenizers don't see the difference.
+pub fn cache_key(text: &str) -> Key {
+    let mut hasher = Sha256::new();
+    for (i, word) in text.split_whitespace().enumerate() {
+        if i > 0 {
+            hasher.update(b" ");
+        }
+        hasher.update(word.as_bytes());
+    }
+    hasher.finalize().into()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Name of the cache file for a model, in the cache directory.
+fn cache_path(dir: &Path, model: &str) -> PathBuf {
+    let name: String = model
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect();
+    dir.join(format!("{}.{}", name, EXTENSION))
+}
+
+/// Start of a cache file, then records of a key, when the record was last used in seconds
+/// since the epoch (u64), and `dimension` f32 values, all little-endian.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Header {
+    model: String,
+    dimension: usize,
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.dimension as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.model.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(self.model.as_bytes());
+        bytes
+    }
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<Header> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut fixed = [0u8; 20];
+        reader.read_exact(&mut fixed)?;
+        let u32_at = |at: usize| u32::from_le_bytes(fixed[at..at + 4].try_into().unwrap());
+        if fixed[..8] != MAGIC[..] {
+            return Err(invalid("not an embedding cache file".to_string()));
+        }
+        if u32_at(8) != VERSION {
+            return Err(invalid(format!("unsupported embedding cache version {}", u32_at(8))));
+        }
+        let mut model = vec![0u8; u32_at(16) as usize];
+        reader.read_exact(&mut model)?;
+        let model = String::from_utf8(model).map_err(|_| invalid("model name isn't utf-8".to_string()))?;
+        Ok(Header { model, dimension: u32_at(12) as usize })
+    }
+
+    fn len(&self) -> usize {
+        20 + self.model.len()
+    }
+
+    fn record_len(&self) -> usize {
+        32 + 8 + self.dimension * 4
+    }
+}
+
+/// Exclusive lock on a cache file, released when dropped, so runs sharing a cache directory
+/// don't write over each other's records. Holds its own handle to the file, so the file it
+/// was taken from can be replaced while it's held.
+struct FileLock(File);
+
+impl FileLock {
+    fn new(file: &File) -> io::Result<FileLock> {
+        let file = file.try_clone()?;
+        file.lock()?;
+        Ok(FileLock(file))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+/// Whether `path` still names the file `file` has open, `prune` replaces cache files with
+/// pruned copies.
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let open = file.metadata()?;
+    match std::fs::metadata(path) {
+        Ok(named) => Ok(open.dev() == named.dev() && open.ino() == named.ino()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Files that are open can't be replaced on other platforms.
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, _path: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Opens and locks the file at `path`, again if it's replaced before the lock is taken.
+fn open_locked(path: &Path, options: &OpenOptions) -> io::Result<(File, FileLock)> {
+    loop {
+        let file = options.open(path)?;
+        let lock = FileLock::new(&file)?;
+        if is_same_file(&file, path)? {
+            return Ok((file, lock));
+        }
+    }
+}
+
+/// Records of a cache file mapped into memory, `(key, last used, values)`.
+struct Records {
+    header: Header,
+    mmap: Option<Mmap>,
+    count: usize,
+}
+
+impl Records {
+    /// Maps the records of a cache file, ignoring a partial record at the end left by an
+    /// interrupted write.
+    fn map(mut file: &File) -> io::Result<Records> {
+        file.seek(SeekFrom::Start(0))?;
+        let header = Header::read(&mut file)?;
+        let len = file.metadata()?.len() as usize;
+        let count = (len - header.len()) / header.record_len();
+        // safety: the cache is only written by appending and by overwriting last used times,
+        // the values that are read from the mapping don't change
+        let mmap = if count > 0 { Some(unsafe { Mmap::map(file)? }) } else { None };
+        Ok(Records { header, mmap, count })
+    }
+
+    fn record(&self, row: usize) -> &[u8] {
+        let start = self.header.len() + row * self.header.record_len();
+        &self.mmap.as_ref().expect("rows only exist when mapped")[start..start + self.header.record_len()]
+    }
+
+    fn key(&self, row: usize) -> Key {
+        self.record(row)[..32].try_into().unwrap()
+    }
+
+    fn last_used(&self, row: usize) -> u64 {
+        u64::from_le_bytes(self.record(row)[32..40].try_into().unwrap())
+    }
+
+    fn values(&self, row: usize) -> Embedding {
+        self.record(row)[40..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect()
+    }
+}
+
+/// Embeddings of texts already encoded by one model, in a file per model in a directory.
+///
+/// New embeddings are appended to the file as soon as they're added, and the last used time of
+/// every record found is updated, so `prune` can drop what hasn't been used for a while. Writes
+/// hold an exclusive lock on the file, several runs can share a cache, and the file is opened
+/// again if `prune` replaced it.
+pub struct EmbeddingCache {
+    path: PathBuf,
+    file: File,
+    records: Records,
+    /// rows in `records`, then rows in `added`
+    index: HashMap<Key, usize>,
+    added: Vec<Embedding>,
+    touched: Vec<usize>,
+}
+
+impl EmbeddingCache {
+    /// Opens the cache for `model` in `dir`, creating both if they don't exist.
+    pub fn open(dir: &Path, model: &str, dimension: usize) -> Result<EmbeddingCache> {
+        let path = cache_path(dir, model);
+        let display = path.display().to_string();
+        let io_error = |e| Error::io(&display, e);
+
+        std::fs::create_dir_all(dir).map_err(|e| Error::io(&dir.display().to_string(), e))?;
+        let (file, lock) = open_locked(&path, OpenOptions::new().read(true).write(true).create(true).truncate(false))
+            .map_err(io_error)?;
+        let header = Header { model: model.to_string(), dimension };
+        if file.metadata().map_err(io_error)?.len() == 0 {
+            (&file).write_all(&header.to_bytes()).map_err(io_error)?;
+        }
+        let records = Records::map(&file).map_err(io_error)?;
+        drop(lock);
+
+        if records.header != header {
+            return Err(Error::parse(
+                &display,
+                format!(
+                    "cache is for {} with dimension {}, not {} with dimension {}",
+                    records.header.model, records.header.dimension, model, dimension
+                ),
+            ));
+        }
+        let index = (0..records.count).map(|row| (records.key(row), row)).collect();
+
+        Ok(EmbeddingCache { path, file, records, index, added: vec![], touched: vec![] })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn get(&mut self, key: &Key) -> Option<Embedding> {
+        let row = *self.index.get(key)?;
+        if row < self.records.count {
+            self.touched.push(row);
+            Some(self.records.values(row))
+        } else {
+            Some(self.added[row - self.records.count].clone())
+        }
+    }
+
+    /// Adds embeddings to the end of the file.
+    pub fn insert(&mut self, entries: &[(Key, &Embedding)]) -> Result<()> {
+        let display = self.path.display().to_string();
+        let used = now().to_le_bytes();
+
+        let _lock = self.lock()?;
+        // other runs may have appended since the file was mapped, and an interrupted write may
+        // have left part of a record, which is written over
+        let header = &self.records.header;
+        let len = self.file.metadata().map_err(|e| Error::io(&display, e))?.len() as usize;
+        let end = header.len() + len.saturating_sub(header.len()) / header.record_len() * header.record_len();
+        (&self.file).seek(SeekFrom::Start(end as u64)).map_err(|e| Error::io(&display, e))?;
+        let mut writer = BufWriter::new(&self.file);
+        for (key, embedding) in entries {
+            if self.index.contains_key(key) {
+                continue;
+            }
+            if embedding.len() != self.records.header.dimension {
+                return Err(Error::DimensionMismatch {
+                    input: display,
+                    row: self.index.len(),
+                    expected: self.records.header.dimension,
+                    found: embedding.len(),
+                });
+            }
+            writer.write_all(key).map_err(|e| Error::io(&display, e))?;
+            writer.write_all(&used).map_err(|e| Error::io(&display, e))?;
+            for value in embedding.iter() {
+                writer.write_all(&value.to_le_bytes()).map_err(|e| Error::io(&display, e))?;
+            }
+            self.index.insert(*key, self.records.count + self.added.len());
+            self.added.push(embedding.to_vec());
+        }
+        writer.flush().map_err(|e| Error::io(&display, e))
+    }
+
+    /// Locks the cache file, first opening it again if `prune` replaced it.
+    fn lock(&mut self) -> Result<FileLock> {
+        let display = self.path.display().to_string();
+        loop {
+            let lock = FileLock::new(&self.file).map_err(|e| Error::io(&display, e))?;
+            if is_same_file(&self.file, &self.path).map_err(|e| Error::io(&display, e))? {
+                return Ok(lock);
+            }
+            drop(lock);
+            self.reopen()?;
+        }
+    }
+
+    /// Opens the file at `path` again, keeping track of the records found since the last flush
+    /// that are still in it.
+    fn reopen(&mut self) -> Result<()> {
+        let dir = self.path.parent().expect("cache files are in the cache directory");
+        let touched: Vec<Key> = self.touched.iter().map(|row| self.records.key(*row)).collect();
+        let mut reopened = EmbeddingCache::open(dir, &self.records.header.model, self.records.header.dimension)?;
+        reopened.touched = touched.iter().filter_map(|key| reopened.index.get(key).copied()).collect();
+        *self = reopened;
+        Ok(())
+    }
+
+    /// Writes the last used time of every record found since the last flush.
+    pub fn flush(&mut self) -> Result<()> {
+        let display = self.path.display().to_string();
+        let used = now().to_le_bytes();
+        self.touched.sort_unstable();
+        self.touched.dedup();
+        if self.touched.is_empty() {
+            return Ok(());
+        }
+        let _lock = self.lock()?;
+        for row in self.touched.drain(..) {
+            let at = self.records.header.len() + row * self.records.header.record_len() + 32;
+            (&self.file).seek(SeekFrom::Start(at as u64)).map_err(|e| Error::io(&display, e))?;
+            (&self.file).write_all(&used).map_err(|e| Error::io(&display, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// An `Embedder` that only encodes texts missing from an `EmbeddingCache`, and adds them to it.
+/// Prints how many texts were found in the cache when dropped.
+pub struct CachedEmbedder {
+    embedder: Box<dyn Embedder>,
+    cache: RefCell<EmbeddingCache>,
+    hits: Cell<usize>,
+    misses: Cell<usize>,
+}
+
+impl CachedEmbedder {
+    /// Caches the embeddings of `embedder` in `dir`.
+    pub fn new(embedder: Box<dyn Embedder>, dir: &Path) -> Result<CachedEmbedder> {
+        let cache = EmbeddingCache::open(dir, &embedder.name(), embedder.dimension())?;
+        Ok(CachedEmbedder { embedder, cache: RefCell::new(cache), hits: Cell::new(0), misses: Cell::new(0) })
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.get()
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.get()
+    }
+}
+
+impl Embedder for CachedEmbedder {
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Embedding>> {
+        let mut cache = self.cache.borrow_mut();
+        let keys: Vec<Key> = texts.iter().map(|text| cache_key(text)).collect();
+        let mut embeddings: Vec<Option<Embedding>> = keys.iter().map(|key| cache.get(key)).collect();
+
+        // each missing text once, in one batch
+        let mut missing: Vec<usize> = (0..texts.len()).filter(|i| embeddings[*i].is_none()).collect();
+        let mut seen = HashMap::new();
+        missing.retain(|i| seen.insert(keys[*i], *i).is_none());
+        self.hits.set(self.hits.get() + texts.len() - missing.len());
+        self.misses.set(self.misses.get() + missing.len());
+
+        if !missing.is_empty() {
+            let encoded = self.embedder.encode(&missing.iter().map(|i| texts[*i]).collect::<Vec<&str>>())?;
+            let entries: Vec<(Key, &Embedding)> = missing.iter().map(|i| keys[*i]).zip(&encoded).collect();
+            cache.insert(&entries)?;
+            for (i, embedding) in missing.iter().zip(encoded) {
+                embeddings[*i] = Some(embedding);
+            }
+        }
+        cache.flush()?;
+
+        Ok(keys
+            .iter()
+            .zip(embeddings)
+            // a text repeated in the batch was only encoded once, the repeats are in the cache now
+            .map(|(key, embedding)| embedding.unwrap_or_else(|| cache.get(key).expect("every missing text was added")))
+            .collect())
+    }
+
+    fn dimension(&self) -> usize {
+        self.embedder.dimension()
+    }
+
+    fn name(&self) -> String {
+        self.embedder.name()
+    }
+
+    fn count_tokens(&self, text: &str) -> Result<Option<usize>> {
+        self.embedder.count_tokens(text)
+    }
+}
+
+impl Drop for CachedEmbedder {
+    fn drop(&mut self) {
+        let total = self.hits() + self.misses();
+        if total > 0 {
+            println!(
+                "embedding cache: {} of {} texts found ({:.1}%), {} entries in {}",
+                self.hits(),
+                total,
+                100.0 * self.hits() as f64 / total as f64,
+                self.cache.borrow().len(),
+                self.cache.borrow().path().display()
+            );
+        }
+    }
+}
+
+/// What's in one model's cache file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    pub path: PathBuf,
+    pub model: String,
+    pub dimension: usize,
+    pub entries: usize,
+    pub bytes: u64,
+    /// Seconds since the epoch the least and most recently used entries were used
+    pub last_used: Option<(u64, u64)>,
+}
+
+/// Every cache file in `dir`, sorted by model.
+fn cache_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let display = dir.display().to_string();
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| Error::io(&display, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|e| e == EXTENSION))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+pub fn stats(dir: &Path) -> Result<Vec<CacheStats>> {
+    cache_files(dir)?
+        .into_iter()
+        .map(|path| {
+            let display = path.display().to_string();
+            let file = File::open(&path).map_err(|e| Error::io(&display, e))?;
+            let records = Records::map(&file).map_err(|e| Error::io(&display, e))?;
+            Ok(stats_of(&path, &records, file.metadata().map_err(|e| Error::io(&display, e))?.len()))
+        })
+        .collect()
+}
+
+/// What to drop from caches. Without any limits `prune` just drops duplicate entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PruneOptions {
+    /// Only prune the cache of this model
+    pub model: Option<String>,
+    /// Drop entries not used for this many seconds
+    pub unused_for: Option<u64>,
+    /// Keep at most this many of the most recently used entries
+    pub max_entries: Option<usize>,
+}
+
+/// Rewrites the cache files in `dir` without the entries `options` drop, returning the stats of
+/// each file before and after.
+pub fn prune(dir: &Path, options: &PruneOptions) -> Result<Vec<(CacheStats, CacheStats)>> {
+    let now = now();
+    let mut pruned = vec![];
+
+    for path in cache_files(dir)? {
+        let display = path.display().to_string();
+        let io_error = |e| Error::io(&display, e);
+        // no run appends while the file is copied
+        let (file, _lock) = open_locked(&path, OpenOptions::new().read(true)).map_err(io_error)?;
+        let records = Records::map(&file).map_err(io_error)?;
+        if options.model.as_ref().is_some_and(|model| *model != records.header.model) {
+            continue;
+        }
+        let before = stats_of(&path, &records, file.metadata().map_err(io_error)?.len());
+
+        // most recently used first, so the first of each key is the one kept
+        let mut rows: Vec<usize> = (0..records.count)
+            .filter(|row| {
+                options.unused_for.is_none_or(|unused_for| records.last_used(*row).saturating_add(unused_for) >= now)
+            })
+            .collect();
+        rows.sort_by_key(|row| std::cmp::Reverse(records.last_used(*row)));
+        let mut seen = std::collections::HashSet::new();
+        rows.retain(|row| seen.insert(records.key(*row)));
+        rows.truncate(options.max_entries.unwrap_or(usize::MAX));
+        rows.sort_unstable();
+
+        let temporary = path.with_extension("pruning");
+        let mut writer = BufWriter::new(File::create(&temporary).map_err(io_error)?);
+        writer.write_all(&records.header.to_bytes()).map_err(io_error)?;
+        for row in &rows {
+            writer.write_all(records.record(*row)).map_err(io_error)?;
+        }
+        writer.flush().map_err(io_error)?;
+        drop(writer);
+        std::fs::rename(&temporary, &path).map_err(io_error)?;
+
+        let file = File::open(&path).map_err(io_error)?;
+        let records = Records::map(&file).map_err(io_error)?;
+        let after = stats_of(&path, &records, file.metadata().map_err(io_error)?.len());
+        pruned.push((before, after));
+    }
+    Ok(pruned)
+}
+
+fn stats_of(path: &Path, records: &Records, bytes: u64) -> CacheStats {
+    let used = (0..records.count).map(|row| records.last_used(row));
+    CacheStats {
+        path: path.to_path_buf(),
+        model: records.header.model.clone(),
+        dimension: records.header.dimension,
+        entries: records.count,
+        bytes,
+        last_used: used.clone().min().zip(used.max()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::HashingEmbedder;
+
+    fn temporary_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cluster-cache-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_keys_ignore_whitespace() {
+        assert_eq!(cache_key("reset my  password"), cache_key(" reset my\tpassword\n"));
+        assert_ne!(cache_key("reset my password"), cache_key("Reset my password"));
+    }
+
+    #[test]
+    fn test_cache_paths_keep_the_whole_model_name() {
+        let dir = Path::new("cache");
+        assert_eq!(dir.join("e5-v1.5.embeddings"), cache_path(dir, "e5-v1.5"));
+        assert_ne!(cache_path(dir, "e5-v1.5"), cache_path(dir, "e5-v1.6"));
+        assert_eq!(dir.join("org_model.embeddings"), cache_path(dir, "org/model"));
+    }
+
+    #[test]
+    fn test_cached_embeddings_match_and_persist() {
+        let dir = temporary_dir("persist");
+        let texts = ["reset my password", "hello", "reset my password", "the app crashes"];
+        let expected = HashingEmbedder::new().encode(&texts).unwrap();
+
+        let cached = CachedEmbedder::new(Box::new(HashingEmbedder::new()), &dir).unwrap();
+        assert_eq!(expected, cached.encode(&texts).unwrap());
+        assert_eq!((1, 3), (cached.hits(), cached.misses()));
+        assert_eq!(expected[..2], cached.encode(&texts[..2]).unwrap()[..]);
+        assert_eq!((3, 3), (cached.hits(), cached.misses()));
+        drop(cached);
+
+        let cached = CachedEmbedder::new(Box::new(HashingEmbedder::new()), &dir).unwrap();
+        assert_eq!(expected, cached.encode(&texts).unwrap());
+        assert_eq!((4, 0), (cached.hits(), cached.misses()));
+        drop(cached);
+
+        let other = CachedEmbedder::new(Box::new(HashingEmbedder::new().with_dimension(8)), &dir).unwrap();
+        assert_eq!(8, other.encode(&["hello"]).unwrap()[0].len());
+        drop(other);
+
+        let stats = stats(&dir).unwrap();
+        assert_eq!(vec![("hashing-384", 3), ("hashing-8", 1)], stats.iter().map(|s| (s.model.as_str(), s.entries)).collect::<Vec<_>>());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_runs_sharing_a_cache_keep_each_others_entries() {
+        let dir = temporary_dir("shared");
+        let (one, two) = (vec![1.0; 4], vec![2.0; 4]);
+        let mut first = EmbeddingCache::open(&dir, "model", 4).unwrap();
+        let mut second = EmbeddingCache::open(&dir, "model", 4).unwrap();
+        first.insert(&[(cache_key("one"), &one)]).unwrap();
+        second.insert(&[(cache_key("two"), &two)]).unwrap();
+
+        let mut cache = EmbeddingCache::open(&dir, "model", 4).unwrap();
+        assert_eq!(Some(one), cache.get(&cache_key("one")));
+        assert_eq!(Some(two), cache.get(&cache_key("two")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_entries_added_after_a_prune_are_kept() {
+        let dir = temporary_dir("prune-while-open");
+        let (one, two) = (vec![1.0; 4], vec![2.0; 4]);
+        let mut cache = EmbeddingCache::open(&dir, "model", 4).unwrap();
+        cache.insert(&[(cache_key("one"), &one)]).unwrap();
+
+        // pruning replaces the file the open cache is writing to
+        assert_eq!(1, prune(&dir, &PruneOptions::default()).unwrap()[0].1.entries);
+        cache.insert(&[(cache_key("two"), &two)]).unwrap();
+        assert_eq!(Some(one.clone()), cache.get(&cache_key("one")));
+        cache.flush().unwrap();
+
+        let mut reopened = EmbeddingCache::open(&dir, "model", 4).unwrap();
+        assert_eq!(2, reopened.len());
+        assert_eq!(Some(one), reopened.get(&cache_key("one")));
+        assert_eq!(Some(two), reopened.get(&cache_key("two")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_it_prunes() {
+        let dir = temporary_dir("prune");
+        let cached = CachedEmbedder::new(Box::new(HashingEmbedder::new()), &dir).unwrap();
+        cached.encode(&["one", "two", "three"]).unwrap();
+        drop(cached);
+
+        let options = PruneOptions { model: Some("other".to_string()), max_entries: Some(0), ..PruneOptions::default() };
+        assert!(prune(&dir, &options).unwrap().is_empty());
+
+        let options = PruneOptions { max_entries: Some(2), ..PruneOptions::default() };
+        let pruned = prune(&dir, &options).unwrap();
+        assert_eq!((3, 2), (pruned[0].0.entries, pruned[0].1.entries));
+
+        let options = PruneOptions { unused_for: Some(3600), ..PruneOptions::default() };
+        assert_eq!(2, prune(&dir, &options).unwrap()[0].1.entries);
+        let options = PruneOptions { unused_for: Some(u64::MAX), ..PruneOptions::default() };
+        assert_eq!(2, prune(&dir, &options).unwrap()[0].1.entries);
+
+        let mut cache = EmbeddingCache::open(&dir, "hashing-384", 384).unwrap();
+        assert_eq!(2, cache.len());
+        assert!(cache.get(&cache_key("three")).is_some() || cache.get(&cache_key("one")).is_some());
+        assert!(EmbeddingCache::open(&dir, "hashing-384", 10).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
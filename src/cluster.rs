@@ -1,32 +1,424 @@
 use std::collections::HashSet;
+use std::str::FromStr;
 
 use rayon::prelude::*;
 use ndarray::prelude::*;
 
+use crate::error::{Error, Result};
+use crate::hnsw::{Hnsw, HnswParams};
+use crate::memory;
 use crate::time_it;
 
-type Embedding = Vec<f32>;
-type Index = usize;
+/// A single sentence embedding.
+pub type Embedding = Vec<f32>;
+/// Row index of a document in the embeddings matrix.
+pub type Index = usize;
+/// Clustering output, `(centroid index, member indices)` sorted largest community first.
 pub type Clusters = Vec<(Index, Vec<Index>)>;
-type Community = (Index, Vec<Index>);
+/// A candidate community, `(centroid index, member indices)`.
+pub type Community = (Index, Vec<Index>);
 
-const MIN_CLUSTER_SIZE: usize = 5;
-const MIN_SIMILARITY: f32 = 0.70;
+/// Rows multiplied at a time by the batched algorithms, when there's no memory budget.
+const BATCH_SIZE: usize = 1000;
 
-fn unique_clusters(communities: &Clusters) -> Clusters {
-    let mut found: Clusters = Vec::new();
+/// Thresholds shared by every clustering algorithm.
+///
+/// A row becomes a candidate community when more than `min_cluster_size` documents
+/// (including itself) have a cosine similarity above `min_similarity` to it.
+///
+/// `max_memory` is a budget in bytes for the batched algorithms, see `BatchPlan`.
+/// `chunks_in_flight` bounds how many batches `BatchedParallel` works on at once, it defaults
+/// to the number of rayon threads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClusterParams {
+    pub min_similarity: f32,
+    pub min_cluster_size: usize,
+    pub max_memory: Option<usize>,
+    pub chunks_in_flight: Option<usize>,
+}
+
+impl Default for ClusterParams {
+    fn default() -> ClusterParams {
+        ClusterParams { min_similarity: 0.70, min_cluster_size: 5, max_memory: None, chunks_in_flight: None }
+    }
+}
+
+impl ClusterParams {
+    /// Sets the cosine similarity a document must exceed to join a community.
+    pub fn with_min_similarity(mut self, min_similarity: f32) -> ClusterParams {
+        self.min_similarity = min_similarity;
+        self
+    }
+
+    /// Sets the number of members a community must exceed to be kept.
+    pub fn with_min_cluster_size(mut self, min_cluster_size: usize) -> ClusterParams {
+        self.min_cluster_size = min_cluster_size;
+        self
+    }
+
+    /// Sets the memory budget, in bytes, for the batched algorithms.
+    pub fn with_max_memory(mut self, max_memory: usize) -> ClusterParams {
+        self.max_memory = Some(max_memory);
+        self
+    }
+
+    /// Sets how many batches `BatchedParallel` multiplies at once.
+    pub fn with_chunks_in_flight(mut self, chunks_in_flight: usize) -> ClusterParams {
+        self.chunks_in_flight = Some(chunks_in_flight);
+        self
+    }
+}
+
+/// How the batched algorithms split a memory budget between the batch of scores being
+/// thresholded, and the communities collected so far.
+///
+/// The budget covers the embeddings matrix, `chunks_in_flight` batches of `batch_size * N`
+/// scores, and the collected communities. When the communities outgrow `communities_limit`
+/// they are pruned to unique communities, like `BatchedPrune` does, which keeps them under N
+/// member indices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchPlan {
+    pub batch_size: usize,
+    pub chunks_in_flight: usize,
+    pub communities_limit: Option<usize>,
+}
+
+impl BatchPlan {
+    pub fn new(n: usize, dim: usize, max_memory: Option<usize>, chunks_in_flight: usize) -> BatchPlan {
+        let max_memory = match max_memory {
+            None => {
+                let chunks_in_flight = chunks_in_flight.max(1);
+                return BatchPlan { batch_size: BATCH_SIZE, chunks_in_flight, communities_limit: None };
+            }
+            Some(max_memory) => max_memory,
+        };
+
+        let fixed = BatchPlan::embeddings_bytes(n, dim) + BatchPlan::pruned_communities_bytes(n);
+        let available = max_memory.saturating_sub(fixed);
+        let score_row = n * std::mem::size_of::<f32>();
+
+        // give half of what's left to the scores, the more room the communities have
+        // the less often they are pruned
+        let score_rows = (available / 2 / score_row.max(1)).max(1);
+        let chunks_in_flight = chunks_in_flight.clamp(1, score_rows);
+        let batch_size = (score_rows / chunks_in_flight).clamp(1, n.max(1));
+        let scores = chunks_in_flight * batch_size * score_row;
+        let communities_limit = max_memory.saturating_sub(BatchPlan::embeddings_bytes(n, dim) + scores);
+
+        BatchPlan { batch_size, chunks_in_flight, communities_limit: Some(communities_limit) }
+    }
+
+    /// Smallest budget the batched algorithms can stay under, multiplying one row at a time.
+    pub fn min_memory(n: usize, dim: usize) -> usize {
+        BatchPlan::embeddings_bytes(n, dim)
+            + BatchPlan::pruned_communities_bytes(n)
+            + n * std::mem::size_of::<f32>()
+    }
+
+    fn embeddings_bytes(n: usize, dim: usize) -> usize {
+        n * dim * std::mem::size_of::<f32>()
+    }
+
+    /// Unique communities hold at most `n` members between them, in vectors that can have up to
+    /// twice the capacity they need. Leave room on top of that for the seen set used while pruning.
+    fn pruned_communities_bytes(n: usize) -> usize {
+        let per_doc = std::mem::size_of::<Community>() + 4 * std::mem::size_of::<Index>();
+        n * per_doc
+    }
+}
+
+/// Collects communities, pruning them to unique communities when they outgrow a byte limit.
+/// Warns once if they're still over it after pruning, `BatchPlan` leaves room for unique
+/// communities so that only happens with a budget below `BatchPlan::min_memory`.
+struct CommunityCollector {
+    communities: Vec<Community>,
+    member_bytes: usize,
+    limit: Option<usize>,
+    warned: bool,
+}
+
+impl CommunityCollector {
+    fn new(limit: Option<usize>) -> CommunityCollector {
+        CommunityCollector { communities: vec![], member_bytes: 0, limit, warned: false }
+    }
+
+    fn bytes(&self) -> usize {
+        self.communities.capacity() * std::mem::size_of::<Community>() + self.member_bytes
+    }
+
+    fn push(&mut self, community: Community) {
+        self.member_bytes += community.1.capacity() * std::mem::size_of::<Index>();
+        self.communities.push(community);
+        if let Some(limit) = self.limit {
+            if self.bytes() > limit {
+                self.prune();
+                if self.bytes() > limit && !self.warned {
+                    eprintln!(
+                        "warning: unique communities take {} bytes, over the {} bytes the memory budget leaves them",
+                        self.bytes(),
+                        limit
+                    );
+                    self.warned = true;
+                }
+            }
+        }
+    }
+
+    fn prune(&mut self) {
+        sort_communities(&mut self.communities);
+        self.communities = unique_clusters(std::mem::take(&mut self.communities));
+        self.communities.shrink_to_fit();
+        self.member_bytes = self.communities
+            .iter()
+            .map(|(_, members)| members.capacity() * std::mem::size_of::<Index>())
+            .sum();
+    }
+
+    fn into_clusters(mut self) -> Clusters {
+        self.prune();
+        self.communities
+    }
+}
+
+/// A clustering strategy, takes a matrix of normalized embeddings (one document per row).
+///
+/// Every algorithm finds the same communities, they differ in how much memory they use and
+/// how fast they run, apart from `BatchedPrune` which can drop communities that overlap
+/// within a batch, and `Ann` which can miss members. With a memory budget `Batched` and
+/// `BatchedParallel` prune the same way once their communities outgrow it, so a tight budget
+/// can make their clusters match `BatchedPrune` rather than `Full`.
+pub trait ClusteringAlgorithm {
+    fn cluster(&self, embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters;
+}
+
+/// One large matrix multiply, uses N^2 memory. See `cluster_using_ndarray`.
+pub struct Full;
+
+/// One vector-matrix multiply per row, lowest memory. See `cluster_using_ndarray_low_memory`.
+pub struct RowWise;
+
+/// Matrix multiplies over batches of rows. See `cluster_using_ndarray_batched`.
+pub struct Batched;
+
+/// Batched, multiplying and thresholding several batches at once with rayon.
+/// See `cluster_using_ndarray_batched_parallel`.
+pub struct BatchedParallel;
+
+/// Only multiplies the blocks on and above the diagonal of the similarity matrix.
+/// See `cluster_using_ndarray_symmetric`.
+pub struct Symmetric;
+
+/// Batched, pruning the communities after each batch. See `cluster_using_ndarray_batched_unique_on_the_go`.
+pub struct BatchedPrune;
+
+impl ClusteringAlgorithm for Full {
+    fn cluster(&self, embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
+        cluster_using_ndarray(embeddings, params)
+    }
+}
+
+impl ClusteringAlgorithm for RowWise {
+    fn cluster(&self, embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
+        cluster_using_ndarray_low_memory(embeddings, params)
+    }
+}
+
+impl ClusteringAlgorithm for Batched {
+    fn cluster(&self, embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
+        cluster_using_ndarray_batched(embeddings, params)
+    }
+}
+
+impl ClusteringAlgorithm for BatchedParallel {
+    fn cluster(&self, embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
+        cluster_using_ndarray_batched_parallel(embeddings, params)
+    }
+}
+
+impl ClusteringAlgorithm for Symmetric {
+    fn cluster(&self, embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
+        cluster_using_ndarray_symmetric(embeddings, params)
+    }
+}
+
+impl ClusteringAlgorithm for BatchedPrune {
+    fn cluster(&self, embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
+        cluster_using_ndarray_batched_unique_on_the_go(embeddings, params)
+    }
+}
+
+/// Approximate, finds each row's neighbours with an HNSW index instead of scoring every pair,
+/// for datasets too big for the N^2 algorithms. See `cluster_using_hnsw`.
+///
+/// Communities can miss members the index didn't find, so after building the index it prints
+/// the recall of `hnsw_recall` over `recall_sample` rows to stderr, unless that's 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ann {
+    pub hnsw: HnswParams,
+    pub recall_sample: usize,
+}
+
+impl Default for Ann {
+    fn default() -> Ann {
+        Ann { hnsw: HnswParams::default(), recall_sample: 1000 }
+    }
+}
+
+impl Ann {
+    pub fn with_hnsw_params(mut self, hnsw: HnswParams) -> Ann {
+        self.hnsw = hnsw;
+        self
+    }
+
+    pub fn with_recall_sample(mut self, recall_sample: usize) -> Ann {
+        self.recall_sample = recall_sample;
+        self
+    }
+}
+
+impl ClusteringAlgorithm for Ann {
+    fn cluster(&self, embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
+        time_it!("hnsw build",
+            let index = Hnsw::build(embeddings, self.hnsw);
+        );
+        if self.recall_sample > 0 {
+            time_it!("recall",
+                let recall = hnsw_recall(&index, embeddings, params, self.recall_sample);
+            );
+            eprintln!(
+                "ann recall {:.4} against exact scores on {} sampled rows",
+                recall,
+                self.recall_sample.min(embeddings.nrows())
+            );
+        }
+        cluster_using_hnsw(&index, embeddings, params)
+    }
+}
+
+/// Picks an algorithm from the number of documents and the memory available.
+///
+/// Batched is as fast as the full N^2 multiply on our benchmarks (see README), so the full
+/// multiply is only used when it fits comfortably in memory and N is small. When even a single
+/// batch of scores won't fit, falls back to the row at a time algorithm. Never picks `Symmetric`,
+/// which can't keep its matches under a memory budget.
+pub struct Auto;
+
+impl Auto {
+    fn select(n: usize, available: Option<usize>) -> Algorithm {
+        let row_bytes = n * std::mem::size_of::<f32>();
+        match available {
+            None => Algorithm::Batched,
+            Some(available) if n <= 10_000 && n * row_bytes <= available / 4 => Algorithm::Full,
+            Some(available) if BATCH_SIZE.min(n) * row_bytes <= available / 2 => Algorithm::Batched,
+            Some(_) => Algorithm::RowWise,
+        }
+    }
+}
+
+impl ClusteringAlgorithm for Auto {
+    fn cluster(&self, embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
+        let available = match (memory::available_memory(), params.max_memory) {
+            (Some(available), Some(max_memory)) => Some(available.min(max_memory)),
+            (available, max_memory) => available.or(max_memory),
+        };
+        let selected = Auto::select(embeddings.nrows(), available);
+        eprintln!("auto selected algorithm {}", selected);
+        selected.build().cluster(embeddings, params)
+    }
+}
+
+/// The clustering algorithms by name, for choosing one at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Full,
+    RowWise,
+    Batched,
+    BatchedParallel,
+    BatchedPrune,
+    Symmetric,
+    Ann,
+    Auto,
+}
+
+impl Algorithm {
+    pub const NAMES: [&'static str; 8] = [
+        "full", "rowwise", "batched", "batched-parallel", "batched-prune", "symmetric", "ann", "auto",
+    ];
+
+    pub fn build(self) -> Box<dyn ClusteringAlgorithm> {
+        match self {
+            Algorithm::Full => Box::new(Full),
+            Algorithm::RowWise => Box::new(RowWise),
+            Algorithm::Batched => Box::new(Batched),
+            Algorithm::BatchedParallel => Box::new(BatchedParallel),
+            Algorithm::BatchedPrune => Box::new(BatchedPrune),
+            Algorithm::Symmetric => Box::new(Symmetric),
+            Algorithm::Ann => Box::new(Ann::default()),
+            Algorithm::Auto => Box::new(Auto),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Full => "full",
+            Algorithm::RowWise => "rowwise",
+            Algorithm::Batched => "batched",
+            Algorithm::BatchedParallel => "batched-parallel",
+            Algorithm::BatchedPrune => "batched-prune",
+            Algorithm::Symmetric => "symmetric",
+            Algorithm::Ann => "ann",
+            Algorithm::Auto => "auto",
+        }
+    }
+}
+
+impl std::fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Algorithm, String> {
+        match s {
+            "full" => Ok(Algorithm::Full),
+            "rowwise" => Ok(Algorithm::RowWise),
+            "batched" => Ok(Algorithm::Batched),
+            "batched-parallel" => Ok(Algorithm::BatchedParallel),
+            "batched-prune" => Ok(Algorithm::BatchedPrune),
+            "symmetric" => Ok(Algorithm::Symmetric),
+            "ann" => Ok(Algorithm::Ann),
+            "auto" => Ok(Algorithm::Auto),
+            _ => Err(format!("unknown algorithm {}, expected one of {}", s, Algorithm::NAMES.join(", "))),
+        }
+    }
+}
+
+/// Sorts by community size, largest first. Ties go to the lowest centroid index, so the
+/// order doesn't depend on the order communities were found in.
+fn sort_communities(communities: &mut [Community]) {
+    communities.sort_unstable_by(|(ia, a), (ib, b)| b.len().cmp(&a.len()).then(ia.cmp(ib)));
+}
+
+/// Keeps communities that don't overlap a community before them, in place.
+fn unique_clusters(mut communities: Clusters) -> Clusters {
     let mut seen: HashSet<Index> = HashSet::new();
 
-    for (centroid_idx, doc_idxs) in communities.iter() {
-        if !doc_idxs.iter().any(|idx| seen.contains(idx)) {
+    communities.retain(|(_, doc_idxs)| {
+        if doc_idxs.iter().any(|idx| seen.contains(idx)) {
+            false
+        } else {
             seen.extend(doc_idxs); // add all doc_idsx to the seen set
-            found.push((centroid_idx.to_owned(), doc_idxs.to_owned()));
+            true
         }
-    }
+    });
 
-    found
+    communities
 }
 
+/// Scales every embedding to unit length, so dot products are cosine similarities.
 pub fn normalize_all_inplace(mut embeddings: Vec<Embedding>) -> Vec<Embedding> {
     fn norm(a: &mut Embedding) {
         let z = a.iter().map(|x| x * x).sum::<f32>().sqrt();
@@ -40,31 +432,66 @@ pub fn normalize_all_inplace(mut embeddings: Vec<Embedding>) -> Vec<Embedding> {
     embeddings
 }
 
-fn count_scores_over_threshold(row: &ArrayView1<f32>) -> usize {
-    row.fold(0, |i, v| if *v > MIN_SIMILARITY { i + 1 } else { i })
+/// Scales every row to unit length in place, the same as `normalize_all_inplace` for a matrix.
+pub fn normalize_rows_inplace(mut embeddings: ArrayViewMut2<f32>) {
+    time_it!(
+        "norm rows inplace",
+        embeddings.axis_iter_mut(Axis(0)).into_par_iter().for_each(|mut row| {
+            let z = row.dot(&row).sqrt();
+            row /= z;
+        });
+    );
+}
+
+fn count_scores_over_threshold(row: &ArrayView1<f32>, min_similarity: f32) -> usize {
+    row.fold(0, |i, v| if *v > min_similarity { i + 1 } else { i })
 }
 
-fn idx_over_threshold(row: &ArrayView1<f32>) -> Vec<usize> {
+fn idx_over_threshold(row: &ArrayView1<f32>, min_similarity: f32) -> Vec<usize> {
     row.indexed_iter()
-        .filter_map(|(i, f)| if f > &MIN_SIMILARITY { Some(i) } else { None })
+        .filter_map(|(i, f)| if *f > min_similarity { Some(i) } else { None })
         .collect()
 }
 
-pub fn vectors_to_array(embeddings: Vec<Embedding>) -> Array2<f32> {
+/// Copies a list of embeddings into a matrix, one embedding per row. The embeddings must all
+/// be the same length, and there must be at least one.
+pub fn vectors_to_array(embeddings: Vec<Embedding>) -> Result<Array2<f32>> {
+    let dimension = match embeddings.first() {
+        Some(first) => first.len(),
+        None => return Err(Error::EmptyInput("embeddings".to_string())),
+    };
+    if let Some((row, embedding)) = embeddings.iter().enumerate().find(|(_, e)| e.len() != dimension) {
+        return Err(Error::DimensionMismatch { input: "embeddings".to_string(), row, expected: dimension, found: embedding.len() });
+    }
+
     let embeddings = Array2::from_shape_vec(
-        (embeddings.len(), embeddings[0].len()),
+        (embeddings.len(), dimension),
         embeddings.into_iter().flatten().collect(),
     ).expect("to get dimension correct");
-    embeddings
+    Ok(embeddings)
+}
+
+/// Checks every index in `clusters` is a row of a matrix with `rows` rows, so they were made
+/// from the same embeddings.
+pub fn check_clusters(clusters: &Clusters, rows: usize) -> Result<()> {
+    let out_of_range = clusters
+        .iter()
+        .flat_map(|(centroid, members)| std::iter::once(centroid).chain(members))
+        .find(|idx| **idx >= rows);
+    match out_of_range {
+        Some(idx) => Err(Error::invalid_parameter(
+            "clusters",
+            format!("index {} is out of range for {} embeddings, were they made from different vectors?", idx, rows),
+        )),
+        None => Ok(()),
+    }
 }
 
 /// This version uses ndarray for faster matrix multiplication
 /// Also optimized the communities stage, python version doesnt actually sort the members in the community
 /// It's back to using N^2 memory though, needs to have the pipeline added back...
 /// ndarray can probably do the normalization for us too...
-pub fn cluster_using_ndarray(embeddings: Vec<Embedding>) -> Clusters {
-    let a = vectors_to_array(embeddings);
-
+pub fn cluster_using_ndarray(a: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
     time_it!("mm",
         let b = a.dot(&a.t());
     );
@@ -73,25 +500,23 @@ pub fn cluster_using_ndarray(embeddings: Vec<Embedding>) -> Clusters {
         let mut c: Vec<Community> = vec![];
         let mut i = 0;
         for row in b.rows() {
-            if count_scores_over_threshold(&row) > MIN_CLUSTER_SIZE {
-                c.push((i, idx_over_threshold(&row)));
+            if count_scores_over_threshold(&row, params.min_similarity) > params.min_cluster_size {
+                c.push((i, idx_over_threshold(&row, params.min_similarity)));
             }
             i = i + 1;
         }
     );
 
-    c.sort_unstable_by(|(_, a), (_, b)| b.len().cmp(&a.len()));
+    sort_communities(&mut c);
 
     time_it!("unique",
-        let found = unique_clusters(&c);
+        let found = unique_clusters(c);
     );
 
     found
 }
 
-pub fn cluster_using_ndarray_low_memory(embeddings: Vec<Embedding>) -> Clusters {
-    // convert list<list<float>> into 2d matrix
-    let embeddings = vectors_to_array(embeddings);
+pub fn cluster_using_ndarray_low_memory(embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
     let embeddings_transposed = embeddings.t().clone();
 
     let mut c: Vec<Community> = embeddings.axis_iter(Axis(0))
@@ -99,74 +524,258 @@ pub fn cluster_using_ndarray_low_memory(embeddings: Vec<Embedding>) -> Clusters
         .enumerate()
         .flat_map(|(doc_index, embedding)| {
             let scores = embedding.dot(&embeddings_transposed);
-            if count_scores_over_threshold(&scores.view()) > MIN_CLUSTER_SIZE {
-                Some((doc_index, idx_over_threshold(&scores.view())))
+            if count_scores_over_threshold(&scores.view(), params.min_similarity) > params.min_cluster_size {
+                Some((doc_index, idx_over_threshold(&scores.view(), params.min_similarity)))
             } else {
                 None
             }
         })
         .collect();
 
-    // sort by community size
-    c.sort_unstable_by(|(_, a), (_, b)| b.len().cmp(&a.len()));
+    sort_communities(&mut c);
 
-    unique_clusters(&c)
+    unique_clusters(c)
 }
 
-pub fn cluster_using_ndarray_batched(embeddings: Vec<Embedding>) -> Clusters {
-    // convert list<list<float>> into 2d matrix
-    let embeddings = vectors_to_array(embeddings);
+/// Multiplies `BatchPlan::batch_size` rows at a time, collecting communities as it goes.
+pub fn cluster_using_ndarray_batched(embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
+    let plan = BatchPlan::new(embeddings.nrows(), embeddings.ncols(), params.max_memory, 1);
+    let embeddings_transposed = embeddings.t();
 
-    let embeddings_transposed = embeddings.t().clone();
-
-    let mut c: Vec<Community> = vec![];
+    let mut c = CommunityCollector::new(plan.communities_limit);
     let mut i = 0;
 
     for scores in embeddings
-        .axis_chunks_iter(Axis(0), 1000)
+        .axis_chunks_iter(Axis(0), plan.batch_size)
         .map(|chunk| chunk.dot(&embeddings_transposed)) {
         for row in scores.rows() {
-            if count_scores_over_threshold(&row) > MIN_CLUSTER_SIZE {
-                c.push((i, idx_over_threshold(&row)));
+            if count_scores_over_threshold(&row, params.min_similarity) > params.min_cluster_size {
+                c.push((i, idx_over_threshold(&row, params.min_similarity)));
             }
-            i = i + 1;
+            i += 1;
         }
         drop(scores);
     }
 
-    c.sort_unstable_by(|(_, a), (_, b)| b.len().cmp(&a.len()));
+    c.into_clusters()
+}
 
-    unique_clusters(&c)
+/// Same as `cluster_using_ndarray_batched`, but multiplies up to `BatchPlan::chunks_in_flight`
+/// batches at once, and thresholds their rows in parallel. Communities are collected in row
+/// order, so the clusters are the same as the sequential version for the same plan.
+pub fn cluster_using_ndarray_batched_parallel(embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
+    let chunks_in_flight = params.chunks_in_flight.unwrap_or_else(rayon::current_num_threads);
+    let plan = BatchPlan::new(embeddings.nrows(), embeddings.ncols(), params.max_memory, chunks_in_flight);
+    let embeddings_transposed = embeddings.t();
+
+    let chunks: Vec<(usize, ArrayView2<f32>)> = embeddings
+        .axis_chunks_iter(Axis(0), plan.batch_size)
+        .enumerate()
+        .map(|(i, chunk)| (i * plan.batch_size, chunk))
+        .collect();
+
+    let mut c = CommunityCollector::new(plan.communities_limit);
+
+    for in_flight in chunks.chunks(plan.chunks_in_flight) {
+        let found: Vec<Vec<Community>> = in_flight
+            .par_iter()
+            .map(|(offset, chunk)| {
+                let scores = chunk.dot(&embeddings_transposed);
+                scores.axis_iter(Axis(0))
+                    .into_par_iter()
+                    .enumerate()
+                    .filter_map(|(i, row)| {
+                        if count_scores_over_threshold(&row, params.min_similarity) > params.min_cluster_size {
+                            Some((offset + i, idx_over_threshold(&row, params.min_similarity)))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        found.into_iter().flatten().for_each(|community| c.push(community));
+    }
+
+    c.into_clusters()
 }
 
-pub fn cluster_using_ndarray_batched_unique_on_the_go(embeddings: Vec<Embedding>) -> Clusters {
-    // convert list<list<float>> into 2d matrix
-    let embeddings = vectors_to_array(embeddings);
-    let embeddings_transposed = embeddings.t().clone();
+/// Cosine similarity is symmetric, so this splits the rows into blocks of `BatchPlan::batch_size`
+/// and only multiplies each block against itself and the blocks after it, roughly halving the
+/// work. Each score over the threshold is credited to both its row and its column.
+///
+/// Every row's matches are held until the last block, up to N^2 indices. The memory budget only
+/// sets the block size, the communities part of it is ignored, unlike the batched algorithms
+/// which prune to stay under it.
+pub fn cluster_using_ndarray_symmetric(embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
+    let plan = BatchPlan::new(embeddings.nrows(), embeddings.ncols(), params.max_memory, 1);
+    symmetric_blocks(embeddings, params, plan.batch_size)
+}
+
+fn symmetric_blocks(embeddings: ArrayView2<f32>, params: &ClusterParams, block_size: usize) -> Clusters {
+    let blocks: Vec<(usize, ArrayView2<f32>)> = embeddings
+        .axis_chunks_iter(Axis(0), block_size)
+        .enumerate()
+        .map(|(i, block)| (i * block_size, block))
+        .collect();
+
+    // blocks are visited in order, so every row's matches are pushed in ascending order,
+    // the same order idx_over_threshold gives
+    let mut matches: Vec<Vec<Index>> = vec![vec![]; embeddings.nrows()];
+
+    for (i, (row_offset, rows)) in blocks.iter().enumerate() {
+        for (column_offset, columns) in &blocks[i..] {
+            let scores = rows.dot(&columns.t());
+            for ((row, column), score) in scores.indexed_iter() {
+                if *score > params.min_similarity {
+                    matches[row_offset + row].push(column_offset + column);
+                    if column_offset != row_offset {
+                        matches[column_offset + column].push(row_offset + row);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut c: Vec<Community> = matches
+        .into_iter()
+        .enumerate()
+        .filter(|(_, members)| members.len() > params.min_cluster_size)
+        .collect();
+
+    sort_communities(&mut c);
+
+    unique_clusters(c)
+}
 
-    let mut c: Vec<Community> = vec![];
+/// Same as `cluster_using_ndarray_batched`, but prunes to unique communities after every batch.
+pub fn cluster_using_ndarray_batched_unique_on_the_go(embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
+    let plan = BatchPlan::new(embeddings.nrows(), embeddings.ncols(), params.max_memory, 1);
+    let embeddings_transposed = embeddings.t();
+
+    let mut c = CommunityCollector::new(plan.communities_limit);
     let mut i = 0;
 
     for scores in embeddings
-        .axis_chunks_iter(Axis(0), 1000)
+        .axis_chunks_iter(Axis(0), plan.batch_size)
         .map(|chunk| chunk.dot(&embeddings_transposed)) {
         for row in scores.rows() {
-            if count_scores_over_threshold(&row) > MIN_CLUSTER_SIZE {
-                c.push((i, idx_over_threshold(&row)));
+            if count_scores_over_threshold(&row, params.min_similarity) > params.min_cluster_size {
+                c.push((i, idx_over_threshold(&row, params.min_similarity)));
             }
-            i = i + 1;
+            i += 1;
         }
 
         drop(scores);
 
-        c.sort_unstable_by(|(_, a), (_, b)| b.len().cmp(&a.len()));
+        c.prune();
+    }
+
+    c.into_clusters()
+}
 
-        c = unique_clusters(&c);
+/// Adds `new` embeddings to `clusters`, a previous clustering of `embeddings`, without
+/// reclustering everything. New rows are numbered after the existing ones, so the first new
+/// row is `embeddings.nrows()`.
+///
+/// Each new row joins the cluster whose centroid it is most similar to, if that's above
+/// `min_similarity`. The rest are clustered among themselves with `cluster_using_ndarray_batched`,
+/// and form new communities. Existing rows that weren't in a cluster aren't looked at again, so
+/// communities of old and new rows are only found by reclustering everything.
+pub fn update_clusters(clusters: Clusters, embeddings: ArrayView2<f32>, new: ArrayView2<f32>, params: &ClusterParams) -> Result<Clusters> {
+    check_clusters(&clusters, embeddings.nrows())?;
+    if new.nrows() > 0 && new.ncols() != embeddings.ncols() {
+        return Err(Error::DimensionMismatch { input: "new vectors".to_string(), row: 0, expected: embeddings.ncols(), found: new.ncols() });
     }
 
-    c
+    let offset = embeddings.nrows();
+    let mut clusters = clusters;
+    let mut unassigned: Vec<Index> = vec![];
+
+    time_it!("assign to centroids",
+        let centroid_idxs: Vec<Index> = clusters.iter().map(|(centroid, _)| *centroid).collect();
+        let centroids = embeddings.select(Axis(0), &centroid_idxs);
+        let mut i = 0;
+        for scores in new
+            .axis_chunks_iter(Axis(0), BATCH_SIZE)
+            .map(|chunk| chunk.dot(&centroids.t())) {
+            for row in scores.rows() {
+                let best = row
+                    .indexed_iter()
+                    .filter(|(_, score)| **score > params.min_similarity)
+                    .max_by(|(_, a), (_, b)| a.total_cmp(b));
+                match best {
+                    Some((cluster, _)) => clusters[cluster].1.push(offset + i),
+                    None => unassigned.push(i),
+                }
+                i += 1;
+            }
+        }
+    );
+
+    time_it!("cluster unassigned",
+        let remaining = new.select(Axis(0), &unassigned);
+        let found = cluster_using_ndarray_batched(remaining.view(), params);
+    );
+
+    clusters.extend(found.into_iter().map(|(centroid, members)| {
+        (offset + unassigned[centroid], members.into_iter().map(|m| offset + unassigned[m]).collect())
+    }));
+
+    sort_communities(&mut clusters);
+
+    Ok(clusters)
+}
+
+/// Finds each row's neighbours above `min_similarity` with an HNSW index built over the same
+/// embeddings, rows are searched in parallel. Approximate, see `hnsw_recall`.
+pub fn cluster_using_hnsw(index: &Hnsw, embeddings: ArrayView2<f32>, params: &ClusterParams) -> Clusters {
+    time_it!("ann search",
+        let mut c: Vec<Community> = embeddings.axis_iter(Axis(0))
+            .into_par_iter()
+            .enumerate()
+            .filter_map(|(doc_index, embedding)| {
+                let members = index.neighbours_above(embedding, params.min_similarity);
+                if members.len() > params.min_cluster_size {
+                    Some((doc_index, members))
+                } else {
+                    None
+                }
+            })
+            .collect();
+    );
+
+    sort_communities(&mut c);
+
+    unique_clusters(c)
 }
 
+/// Fraction of the neighbours above `min_similarity` that the index finds, out of the ones
+/// `cluster_using_ndarray_batched` would find, for `sample` rows spread evenly through the
+/// embeddings.
+pub fn hnsw_recall(index: &Hnsw, embeddings: ArrayView2<f32>, params: &ClusterParams, sample: usize) -> f32 {
+    let n = embeddings.nrows();
+    let rows: Vec<Index> = (0..n).step_by((n / sample.max(1)).max(1)).take(sample).collect();
+    let sampled = embeddings.select(Axis(0), &rows);
+
+    let mut exact = 0;
+    let mut found = 0;
+    for (rows, chunk) in rows.chunks(BATCH_SIZE).zip(sampled.axis_chunks_iter(Axis(0), BATCH_SIZE)) {
+        let scores = chunk.dot(&embeddings.t());
+        for (row, scores) in rows.iter().zip(scores.rows()) {
+            let neighbours: HashSet<Index> = index
+                .neighbours_above(embeddings.row(*row), params.min_similarity)
+                .into_iter()
+                .collect();
+            let expected = idx_over_threshold(&scores, params.min_similarity);
+            exact += expected.len();
+            found += expected.iter().filter(|i| neighbours.contains(i)).count();
+        }
+    }
+
+    if exact == 0 { 1.0 } else { found as f32 / exact as f32 }
+}
 
 #[cfg(test)]
 mod tests {
@@ -175,25 +784,287 @@ mod tests {
     #[test]
     fn test_it_can_count_scores_over_threshold() {
         let a = array![1.0, 0.0, 0.1, 2.0, 0.2, 0.0, 0.0];
-        assert_eq!(2, count_scores_over_threshold(&a.view()));
+        assert_eq!(2, count_scores_over_threshold(&a.view(), 0.70));
 
         let a = array![];
-        assert_eq!(0, count_scores_over_threshold(&a.view()));
+        assert_eq!(0, count_scores_over_threshold(&a.view(), 0.70));
 
         let a = array![0.0, 0.0, 0.1];
-        assert_eq!(0, count_scores_over_threshold(&a.view()));
+        assert_eq!(0, count_scores_over_threshold(&a.view(), 0.70));
+
+        let a = array![1.0, 0.0, 0.1, 2.0, 0.2, 0.0, 0.0];
+        assert_eq!(4, count_scores_over_threshold(&a.view(), 0.05));
     }
 
     #[test]
     fn test_it_can_collect_idx_over_threshold() {
         let a = array![];
-        assert_eq!(Vec::<usize>::new(), idx_over_threshold(&a.view()));
+        assert_eq!(Vec::<usize>::new(), idx_over_threshold(&a.view(), 0.70));
 
         let a = array![0.0, 0.0, 0.1];
-        assert_eq!(Vec::<usize>::new(), idx_over_threshold(&a.view()));
+        assert_eq!(Vec::<usize>::new(), idx_over_threshold(&a.view(), 0.70));
 
         let a = array![1.0, 0.0, 0.1, 2.0, 0.2, 0.0, 0.0];
-        assert_eq!(vec![0 as usize, 3 as usize], idx_over_threshold(&a.view()));
+        assert_eq!(vec![0 as usize, 3 as usize], idx_over_threshold(&a.view(), 0.70));
+        assert_eq!(vec![0, 2, 3, 4], idx_over_threshold(&a.view(), 0.05));
+    }
+
+    #[test]
+    fn test_it_clusters_using_params() {
+        // 4 identical vectors, and 3 more close to them
+        let mut input = vec![vec![1.0, 0.0]; 4];
+        input.extend(vec![vec![1.0, 0.8]; 3]);
+        let input = vectors_to_array(normalize_all_inplace(input)).unwrap();
+        let input = input.view();
+
+        let strict = ClusterParams::default().with_min_similarity(0.95).with_min_cluster_size(3);
+        let loose = ClusterParams::default().with_min_similarity(0.70).with_min_cluster_size(3);
+
+        let expect_strict: Clusters = vec![(0, vec![0, 1, 2, 3])];
+        let expect_loose: Clusters = vec![(0, vec![0, 1, 2, 3, 4, 5, 6])];
+
+        assert_eq!(expect_strict, cluster_using_ndarray(input, &strict));
+        assert_eq!(expect_loose, cluster_using_ndarray(input, &loose));
+        assert_eq!(expect_strict, cluster_using_ndarray_low_memory(input, &strict));
+        assert_eq!(expect_loose, cluster_using_ndarray_low_memory(input, &loose));
+        assert_eq!(expect_strict, cluster_using_ndarray_batched(input, &strict));
+        assert_eq!(expect_loose, cluster_using_ndarray_batched(input, &loose));
+        assert_eq!(expect_strict, cluster_using_ndarray_batched_unique_on_the_go(input, &strict));
+        assert_eq!(expect_loose, cluster_using_ndarray_batched_unique_on_the_go(input, &loose));
+
+        let too_big = ClusterParams::default().with_min_similarity(0.70).with_min_cluster_size(7);
+        let same = vectors_to_array(normalize_all_inplace(vec![vec![1.0, 0.0]; 7])).unwrap();
+        assert_eq!(Clusters::new(), cluster_using_ndarray(same.view(), &too_big));
+    }
+
+    #[test]
+    fn test_all_algorithms_agree() {
+        let input = vec![vec![1.0, 0.0]; 7];
+        let input = vectors_to_array(normalize_all_inplace(input)).unwrap();
+        let params = ClusterParams::default();
+
+        for name in Algorithm::NAMES {
+            let algorithm = name.parse::<Algorithm>().unwrap();
+            assert_eq!(name, algorithm.name());
+            assert_eq!(vec![(0, vec![0, 1, 2, 3, 4, 5, 6])], algorithm.build().cluster(input.view(), &params));
+        }
+        assert!("ndarray".parse::<Algorithm>().is_err());
+    }
+
+    #[test]
+    fn test_auto_selects_algorithm_from_memory() {
+        let gb = 1024 * 1024 * 1024;
+        assert_eq!(Algorithm::Batched, Auto::select(5_000, None));
+        assert_eq!(Algorithm::Full, Auto::select(5_000, Some(gb)));
+        assert_eq!(Algorithm::Batched, Auto::select(40_000, Some(gb)));
+        assert_eq!(Algorithm::RowWise, Auto::select(1_000_000, Some(gb)));
+        for n in [100, 5_000, 40_000, 1_000_000] {
+            for available in [None, Some(gb / 1024), Some(gb), Some(64 * gb)] {
+                assert_ne!(Algorithm::Symmetric, Auto::select(n, available));
+            }
+        }
+    }
+
+    /// Random unit vectors scattered around `centres` random centres, deterministic for a seed.
+    fn clustered_embeddings(n: usize, dim: usize, centres: usize, seed: u64) -> Array2<f32> {
+        let mut state = seed.max(1);
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % 10_000) as f32 / 5_000.0 - 1.0
+        };
+
+        let centres: Vec<Embedding> = (0..centres).map(|_| (0..dim).map(|_| random()).collect()).collect();
+        let embeddings = (0..n)
+            .map(|i| centres[i % centres.len()].iter().map(|x| x + 0.3 * random()).collect())
+            .collect();
+        vectors_to_array(normalize_all_inplace(embeddings)).unwrap()
+    }
+
+    #[test]
+    fn test_batch_plan_fits_budget() {
+        assert_eq!(
+            BatchPlan { batch_size: 1000, chunks_in_flight: 1, communities_limit: None },
+            BatchPlan::new(40_000, 384, None, 1)
+        );
+        assert_eq!(1, BatchPlan::new(40_000, 384, None, 0).chunks_in_flight);
+
+        let mb = 1024 * 1024;
+        let plan = BatchPlan::new(40_000, 384, Some(512 * mb), 1);
+        assert!(plan.batch_size > 1000);
+        assert!(plan.batch_size < 40_000);
+        let scores = plan.batch_size * 40_000 * 4;
+        assert!(40_000 * 384 * 4 + scores + plan.communities_limit.unwrap() <= 512 * mb);
+
+        let plan = BatchPlan::new(100, 8, Some(BatchPlan::min_memory(100, 8)), 4);
+        assert_eq!(1, plan.batch_size);
+        assert_eq!(1, plan.chunks_in_flight);
+
+        assert_eq!(100, BatchPlan::new(100, 8, Some(1024 * mb), 1).batch_size);
+
+        let plan = BatchPlan::new(40_000, 384, Some(512 * mb), 4);
+        assert_eq!(4, plan.chunks_in_flight);
+        let scores = plan.chunks_in_flight * plan.batch_size * 40_000 * 4;
+        assert!(40_000 * 384 * 4 + scores + plan.communities_limit.unwrap() <= 512 * mb);
+    }
+
+    #[test]
+    fn test_batched_algorithms_stay_under_budget() {
+        let embeddings = clustered_embeddings(500, 16, 20, 7);
+        let params = ClusterParams::default();
+        let expected = cluster_using_ndarray(embeddings.view(), &params);
+        assert!(!expected.is_empty());
+
+        // batches of 100 rows, with room to collect every community without pruning
+        let budget = BatchPlan::min_memory(500, 16) + 2 * 100 * 500 * 4;
+        let plan = BatchPlan::new(500, 16, Some(budget), 1);
+        assert_eq!(100, plan.batch_size);
+
+        assert_eq!(expected, cluster_using_ndarray_batched(embeddings.view(), &params));
+
+        let budgeted = params.with_max_memory(budget);
+        assert_eq!(expected, cluster_using_ndarray_batched(embeddings.view(), &budgeted));
+        assert_eq!(expected, cluster_using_ndarray_batched_unique_on_the_go(embeddings.view(), &budgeted));
+    }
+
+    #[test]
+    fn test_tight_budgets_prune_like_batched_prune() {
+        let embeddings = clustered_embeddings(300, 8, 10, 1);
+        let params = ClusterParams::default().with_min_similarity(0.6);
+        let budgeted = params.with_max_memory(BatchPlan::min_memory(300, 8));
+        let pruned = cluster_using_ndarray_batched_unique_on_the_go(embeddings.view(), &budgeted);
+
+        assert_ne!(cluster_using_ndarray(embeddings.view(), &params), pruned);
+        assert_eq!(pruned, cluster_using_ndarray_batched(embeddings.view(), &budgeted));
+        assert_eq!(pruned, cluster_using_ndarray_batched_parallel(embeddings.view(), &budgeted.with_chunks_in_flight(2)));
+    }
+
+    #[test]
+    fn test_parallel_batches_match_sequential() {
+        let embeddings = clustered_embeddings(2_500, 16, 40, 11);
+        let params = ClusterParams::default();
+        let expected = cluster_using_ndarray_batched(embeddings.view(), &params);
+        assert!(expected.len() > 1);
+
+        for chunks_in_flight in [1, 2, 3, 8] {
+            let params = params.with_chunks_in_flight(chunks_in_flight);
+            assert_eq!(expected, cluster_using_ndarray_batched_parallel(embeddings.view(), &params));
+        }
+
+        let budget = BatchPlan::min_memory(2_500, 16) + 2 * 300 * 2_500 * 4;
+        let params = params.with_max_memory(budget).with_chunks_in_flight(3);
+        assert_eq!(100, BatchPlan::new(2_500, 16, Some(budget), 3).batch_size);
+        assert_eq!(expected, cluster_using_ndarray_batched_parallel(embeddings.view(), &params));
+    }
+
+    #[test]
+    fn test_symmetric_blocks_match_full() {
+        let embeddings = clustered_embeddings(1_000, 16, 30, 5);
+        let params = ClusterParams::default();
+        let expected = cluster_using_ndarray(embeddings.view(), &params);
+        assert!(expected.len() > 1);
+
+        for block_size in [7, 100, 999, 1000, 5000] {
+            assert_eq!(expected, symmetric_blocks(embeddings.view(), &params, block_size));
+        }
+        assert_eq!(expected, cluster_using_ndarray_symmetric(embeddings.view(), &params));
+
+        let params = params.with_min_similarity(0.9).with_min_cluster_size(2);
+        assert_eq!(cluster_using_ndarray(embeddings.view(), &params), symmetric_blocks(embeddings.view(), &params, 64));
+    }
+
+    #[test]
+    fn test_ann_matches_batched() {
+        let embeddings = clustered_embeddings(2_000, 16, 40, 11);
+        let params = ClusterParams::default();
+        let index = Hnsw::build(embeddings.view(), HnswParams::default());
+
+        assert_eq!(1.0, hnsw_recall(&index, embeddings.view(), &params, 200));
+        assert_eq!(
+            cluster_using_ndarray_batched(embeddings.view(), &params),
+            cluster_using_hnsw(&index, embeddings.view(), &params)
+        );
+    }
+
+    #[test]
+    fn test_update_clusters() {
+        let embeddings = vectors_to_array(normalize_all_inplace(vec![vec![1.0, 0.0]; 7])).unwrap();
+        let params = ClusterParams::default();
+        let clusters = cluster_using_ndarray_batched(embeddings.view(), &params);
+
+        let new = vectors_to_array(normalize_all_inplace(vec![
+            vec![0.0, 1.0], vec![0.0, 1.0], vec![0.9, 0.1],
+            vec![0.0, 1.0], vec![0.0, 1.0], vec![0.0, 1.0], vec![0.0, 1.0], vec![-1.0, 0.0],
+        ])).unwrap();
+        let expected = vec![
+            (0, vec![0, 1, 2, 3, 4, 5, 6, 9]),
+            (7, vec![7, 8, 10, 11, 12, 13]),
+        ];
+        assert_eq!(expected, update_clusters(clusters.clone(), embeddings.view(), new.view(), &params).unwrap());
+
+        let wrong_dimension = Array2::<f32>::zeros((1, 3));
+        assert!(matches!(
+            update_clusters(clusters.clone(), embeddings.view(), wrong_dimension.view(), &params),
+            Err(Error::DimensionMismatch { expected: 2, found: 3, .. })
+        ));
+        assert!(update_clusters(clusters, new.view(), new.view(), &params).is_ok());
+        assert!(matches!(
+            update_clusters(vec![(20, vec![20])], embeddings.view(), new.view(), &params),
+            Err(Error::InvalidParameter { name: "clusters", .. })
+        ));
+    }
+
+    #[test]
+    fn test_update_assigns_rows_to_existing_centroids() {
+        let all = clustered_embeddings(1_000, 16, 10, 7);
+        let (old, new) = all.view().split_at(Axis(0), 500);
+        let params = ClusterParams::default().with_min_similarity(0.8);
+
+        // every centre has rows in both halves, so every new row has an existing centroid
+        let clusters = cluster_using_ndarray_batched(old, &params);
+        assert_eq!(10, clusters.len());
+        let updated = update_clusters(clusters.clone(), old, new, &params).unwrap();
+
+        assert_eq!(10, updated.len());
+        for ((centroid, members), (updated_centroid, updated_members)) in clusters.iter().zip(&updated) {
+            assert_eq!(centroid, updated_centroid);
+            assert_eq!(members.len() * 2, updated_members.len());
+        }
+    }
+
+    #[test]
+    fn test_collector_prunes_over_limit() {
+        let mut c = CommunityCollector::new(Some(1024));
+        for i in 0..100 {
+            c.push((i, vec![0, 1, 2, 3]));
+            assert!(c.bytes() <= 1024);
+        }
+        assert_eq!(vec![(0, vec![0, 1, 2, 3])], c.into_clusters());
+
+        let mut c = CommunityCollector::new(None);
+        for i in 0..100 {
+            c.push((i, vec![0, 1, 2, 3]));
+        }
+        assert_eq!(100, c.communities.len());
+    }
+
+    #[test]
+    fn test_vectors_to_array_checks_dimensions() {
+        assert!(matches!(vectors_to_array(vec![]), Err(Error::EmptyInput(_))));
+        assert!(matches!(
+            vectors_to_array(vec![vec![1.0, 0.0], vec![1.0, 0.0], vec![1.0]]),
+            Err(Error::DimensionMismatch { row: 2, expected: 2, found: 1, .. })
+        ));
+        assert_eq!((2, 1), vectors_to_array(vec![vec![1.0], vec![2.0]]).unwrap().dim());
+    }
+
+    #[test]
+    fn test_it_can_normalize_rows() {
+        let input = vec![vec![2.0, 5.0, -1.5], vec![4.0, 2.0, -2.0]];
+        let mut rows = vectors_to_array(input.clone()).unwrap();
+        normalize_rows_inplace(rows.view_mut());
+        assert_eq!(vectors_to_array(normalize_all_inplace(input)).unwrap(), rows);
     }
 
     #[test]
@@ -0,0 +1,270 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use crate::error::{Error, Result};
+use crate::file;
+use crate::time_it;
+
+/// Formats documents can be read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextFormat {
+    /// One document per line
+    Lines,
+    /// CSV with a header row, quoted fields can span lines
+    Csv,
+    /// A json object per line
+    Jsonl,
+}
+
+impl TextFormat {
+    pub const NAMES: [&'static str; 3] = ["lines", "csv", "jsonl"];
+
+    /// Picks the format from the file extension, `.csv`, `.jsonl` or `.ndjson`, anything else
+    /// is lines.
+    pub fn detect(filename: &str) -> TextFormat {
+        let extension = Path::new(filename)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("csv") => TextFormat::Csv,
+            Some("jsonl") | Some("ndjson") => TextFormat::Jsonl,
+            _ => TextFormat::Lines,
+        }
+    }
+}
+
+impl FromStr for TextFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<TextFormat, String> {
+        match s {
+            "lines" => Ok(TextFormat::Lines),
+            "csv" => Ok(TextFormat::Csv),
+            "jsonl" => Ok(TextFormat::Jsonl),
+            _ => Err(format!("unknown format {}, expected one of {}", s, TextFormat::NAMES.join(", "))),
+        }
+    }
+}
+
+/// Documents read from a file, in file order. Row `i` of embeddings made from `texts` is
+/// document `i`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Documents {
+    pub texts: Vec<String>,
+    /// The id of each document, when the file has them
+    pub ids: Option<Vec<String>>,
+}
+
+impl Documents {
+    pub fn len(&self) -> usize {
+        self.texts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.texts.is_empty()
+    }
+
+    pub fn id(&self, index: usize) -> Option<&str> {
+        self.ids.as_ref().map(|ids| ids[index].as_str())
+    }
+}
+
+/// Configures where documents are found in a file, then reads them with `load`.
+///
+/// A CSV column is a header name, or a number counting from 1 like `csvtool col`, the text
+/// defaults to a column called `text`, or the only column. A JSONL field is a key of the
+/// objects, the text defaults to `text`. Ids are optional, and are read as strings.
+#[derive(Debug, Clone, Default)]
+pub struct TextInput {
+    format: Option<TextFormat>,
+    text_field: Option<String>,
+    id_field: Option<String>,
+}
+
+impl TextInput {
+    pub fn new() -> TextInput {
+        TextInput::default()
+    }
+
+    /// Format of the file, detected from its extension when not set.
+    pub fn with_format(mut self, format: TextFormat) -> TextInput {
+        self.format = Some(format);
+        self
+    }
+
+    /// CSV column or JSONL field holding the text.
+    pub fn with_text_field(mut self, text_field: &str) -> TextInput {
+        self.text_field = Some(text_field.to_string());
+        self
+    }
+
+    /// CSV column or JSONL field holding the document id.
+    pub fn with_id_field(mut self, id_field: &str) -> TextInput {
+        self.id_field = Some(id_field.to_string());
+        self
+    }
+
+    pub fn load(&self, filename: &str) -> Result<Documents> {
+        match self.format.unwrap_or_else(|| TextFormat::detect(filename)) {
+            TextFormat::Lines => {
+                if self.text_field.is_some() || self.id_field.is_some() {
+                    return Err(Error::invalid_parameter(
+                        "text-column",
+                        format!("{} is read as lines of text, columns and fields are for csv and jsonl", filename),
+                    ));
+                }
+                Ok(Documents { texts: file::load_lines(filename)?, ids: None })
+            }
+            TextFormat::Csv => {
+                time_it!(
+                    "reading csv",
+                    let reader = File::open(filename).map_err(|e| Error::io(filename, e))?;
+                    let documents = self.read_csv(filename, reader)?;
+                    println!("loaded {} rows", documents.len());
+                );
+                Ok(documents)
+            }
+            TextFormat::Jsonl => {
+                time_it!(
+                    "reading jsonl",
+                    let reader = File::open(filename).map_err(|e| Error::io(filename, e))?;
+                    let documents = self.read_jsonl(filename, BufReader::new(reader))?;
+                    println!("loaded {} lines", documents.len());
+                );
+                Ok(documents)
+            }
+        }
+    }
+
+    /// Reads CSV with a header row, skipping rows with nothing in them.
+    fn read_csv<R: std::io::Read>(&self, filename: &str, reader: R) -> Result<Documents> {
+        let csv_error = |e: csv::Error| {
+            if e.is_io_error() {
+                Error::io(filename, e.into())
+            } else {
+                Error::parse(filename, e)
+            }
+        };
+
+        let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+        let headers = reader.headers().map_err(csv_error)?.clone();
+        let text_column = match &self.text_field {
+            Some(column) => csv_column(filename, &headers, column, "text-column")?,
+            None if headers.len() == 1 => 0,
+            None => csv_column(filename, &headers, "text", "text-column")?,
+        };
+        let id_column = self
+            .id_field
+            .as_ref()
+            .map(|column| csv_column(filename, &headers, column, "id-column"))
+            .transpose()?;
+
+        let mut documents = Documents { texts: vec![], ids: id_column.map(|_| vec![]) };
+        for record in reader.records() {
+            let record = record.map_err(csv_error)?;
+            if record.iter().all(str::is_empty) {
+                continue;
+            }
+            let field = |column: usize| {
+                record.get(column).ok_or_else(|| {
+                    let line = record.position().map_or(0, |p| p.line());
+                    Error::parse(filename, format!("row on line {} has {} columns, expected at least {}", line, record.len(), column + 1))
+                })
+            };
+            documents.texts.push(field(text_column)?.to_string());
+            if let (Some(ids), Some(id_column)) = (&mut documents.ids, id_column) {
+                ids.push(field(id_column)?.to_string());
+            }
+        }
+        Ok(documents)
+    }
+
+    /// Reads a json object per line, skipping blank lines.
+    fn read_jsonl<R: BufRead>(&self, filename: &str, reader: R) -> Result<Documents> {
+        let text_field = self.text_field.as_deref().unwrap_or("text");
+        let mut documents = Documents { texts: vec![], ids: self.id_field.as_ref().map(|_| vec![]) };
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| Error::io(filename, e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let number = number + 1;
+            let object: Value = serde_json::from_str(&line)
+                .map_err(|e| Error::parse(filename, format!("line {}: {}", number, e)))?;
+
+            match object.get(text_field) {
+                Some(Value::String(text)) => documents.texts.push(text.clone()),
+                _ => return Err(Error::parse(filename, format!("line {}: no string field {}", number, text_field))),
+            }
+            if let (Some(ids), Some(id_field)) = (&mut documents.ids, &self.id_field) {
+                match object.get(id_field) {
+                    Some(Value::String(id)) => ids.push(id.clone()),
+                    Some(id @ Value::Number(_)) => ids.push(id.to_string()),
+                    _ => return Err(Error::parse(filename, format!("line {}: no string or number field {}", number, id_field))),
+                }
+            }
+        }
+        Ok(documents)
+    }
+}
+
+/// Index of a CSV column given by header name, or by number counting from 1.
+fn csv_column(filename: &str, headers: &csv::StringRecord, column: &str, name: &'static str) -> Result<usize> {
+    if let Some(index) = headers.iter().position(|h| h == column) {
+        return Ok(index);
+    }
+    match column.parse::<usize>() {
+        Ok(number) if (1..=headers.len()).contains(&number) => Ok(number - 1),
+        _ => Err(Error::invalid_parameter(
+            name,
+            format!("{} has no column {}, the columns are {}", filename, column, headers.iter().collect::<Vec<_>>().join(", ")),
+        )),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_it_detects_formats_from_extensions() {
+        assert_eq!(TextFormat::Csv, TextFormat::detect("data.CSV"));
+        assert_eq!(TextFormat::Jsonl, TextFormat::detect("chats.ndjson"));
+        assert_eq!(TextFormat::Lines, TextFormat::detect("10k.txt"));
+    }
+
+    #[test]
+    fn test_it_can_read_csv() {
+        let csv = "id,subject,body\nT-1,login,\"can't log in\"\n\n,,\nT-2,\"multi\nline\",\"first line\nsecond line\"\n";
+        let input = TextInput::new().with_text_field("body").with_id_field("1");
+
+        let documents = input.read_csv("test.csv", csv.as_bytes()).unwrap();
+        assert_eq!(vec!["can't log in", "first line\nsecond line"], documents.texts);
+        assert_eq!(Some("T-2"), documents.id(1));
+
+        let documents = TextInput::new().read_csv("test.csv", "text\nhello\nworld\n".as_bytes()).unwrap();
+        assert_eq!(vec!["hello", "world"], documents.texts);
+        assert_eq!(None, documents.ids);
+
+        assert!(TextInput::new().read_csv("test.csv", csv.as_bytes()).is_err());
+        assert!(input.read_csv("test.csv", "id,subject,body\nT-1,short\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_it_can_read_jsonl() {
+        let jsonl = "{\"id\": 7, \"text\": \"hello\"}\n\n  \n{\"id\": \"a\", \"text\": \"two\\nlines\"}\n";
+        let documents = TextInput::new().with_id_field("id").read_jsonl("test.jsonl", jsonl.as_bytes()).unwrap();
+        assert_eq!(vec!["hello", "two\nlines"], documents.texts);
+        assert_eq!(Some(vec!["7".to_string(), "a".to_string()]), documents.ids);
+
+        let error = TextInput::new().with_text_field("body").read_jsonl("test.jsonl", jsonl.as_bytes()).unwrap_err();
+        assert_eq!("test.jsonl: line 1: no string field body", error.to_string());
+        assert!(TextInput::new().read_jsonl("test.jsonl", "{\"text\": ".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use std::sync::Mutex;
+
+use crate::cluster::Embedding;
+use crate::error::{Error, Result};
+
+/// Turns text into embeddings. Implemented by the rust-bert sentence embeddings models, see
+/// `model::ModelSource`, and by `HashingEmbedder`, which needs no model files.
+pub trait Embedder {
+    /// One embedding per text, each `dimension()` long, not necessarily normalized.
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Embedding>>;
+
+    fn dimension(&self) -> usize;
+
+    /// Name of the model, written into binary vector files.
+    fn name(&self) -> String;
+
+    /// How many tokens the model's tokenizer splits `text` into, leaving out special tokens,
+    /// `None` for models without a tokenizer.
+    fn count_tokens(&self, _text: &str) -> Result<Option<usize>> {
+        Ok(None)
+    }
+}
+
+/// Default dimension of `HashingEmbedder`, the same as all-MiniLM-L6-v2.
+pub const HASHING_DIMENSION: usize = 384;
+
+/// Character n-grams count for less than whole words, so related word forms still overlap.
+const TRIGRAM_WEIGHT: f32 = 0.5;
+
+/// Embeds text by hashing its words and character trigrams into a fixed number of buckets,
+/// the "hashing trick". No model files and no training, and the same text always gets the same
+/// embedding, so it suits minimal builds and tests. Texts sharing words are similar, but
+/// synonyms and paraphrases aren't, unlike with a sentence embeddings model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashingEmbedder {
+    dimension: usize,
+}
+
+impl Default for HashingEmbedder {
+    fn default() -> HashingEmbedder {
+        HashingEmbedder { dimension: HASHING_DIMENSION }
+    }
+}
+
+impl HashingEmbedder {
+    pub fn new() -> HashingEmbedder {
+        HashingEmbedder::default()
+    }
+
+    /// Number of buckets, fewer means more unrelated features collide.
+    pub fn with_dimension(mut self, dimension: usize) -> HashingEmbedder {
+        self.dimension = dimension.max(1);
+        self
+    }
+
+    /// Lowercase words, split at anything that isn't a letter or a digit.
+    fn words(text: &str) -> impl Iterator<Item = &str> {
+        text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty())
+    }
+
+    fn embed(&self, text: &str) -> Embedding {
+        let mut embedding = vec![0.0; self.dimension];
+        let mut add = |feature: &[u8], weight: f32| {
+            let hash = fnv1a(feature);
+            // the top bit picks the sign, so collisions cancel out on average
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            embedding[(hash % self.dimension as u64) as usize] += sign * weight;
+        };
+
+        let lowercase = text.to_lowercase();
+        for word in HashingEmbedder::words(&lowercase) {
+            add(word.as_bytes(), 1.0);
+
+            let padded: Vec<char> = std::iter::once('<').chain(word.chars()).chain(std::iter::once('>')).collect();
+            for trigram in padded.windows(3) {
+                add(trigram.iter().collect::<String>().as_bytes(), TRIGRAM_WEIGHT);
+            }
+        }
+        // text without words, or whose features cancel out, gets the same fixed vector rather
+        // than zeros, which have no direction and normalize to NaN
+        if embedding.iter().all(|v| *v == 0.0) {
+            embedding[0] = 1.0;
+        }
+        embedding
+    }
+}
+
+impl Embedder for HashingEmbedder {
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Embedding>> {
+        Ok(texts.iter().map(|text| self.embed(text)).collect())
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn name(&self) -> String {
+        format!("hashing-{}", self.dimension)
+    }
+
+    /// The words that are hashed.
+    fn count_tokens(&self, text: &str) -> Result<Option<usize>> {
+        Ok(Some(HashingEmbedder::words(text).count()))
+    }
+}
+
+/// Several instances of the same model, encoding disjoint parts of each batch on their own
+/// threads. The embeddings come back in the order of the texts, whichever instance is first.
+pub struct ParallelEmbedder {
+    instances: Vec<Mutex<Box<dyn Embedder + Send>>>,
+    dimension: usize,
+    name: String,
+}
+
+impl ParallelEmbedder {
+    /// The instances must all be the same model.
+    pub fn new(instances: Vec<Box<dyn Embedder + Send>>) -> Result<ParallelEmbedder> {
+        let first = instances
+            .first()
+            .ok_or_else(|| Error::invalid_parameter("model-instances", "at least one model instance is needed"))?;
+        let (dimension, name) = (first.dimension(), first.name());
+        if let Some(other) = instances.iter().find(|instance| instance.dimension() != dimension || instance.name() != name) {
+            return Err(Error::invalid_parameter(
+                "model-instances",
+                format!("instances of different models, {} and {}", name, other.name()),
+            ));
+        }
+        let instances = instances.into_iter().map(Mutex::new).collect();
+        Ok(ParallelEmbedder { instances, dimension, name })
+    }
+
+    pub fn instances(&self) -> usize {
+        self.instances.len()
+    }
+}
+
+impl Embedder for ParallelEmbedder {
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Embedding>> {
+        if texts.is_empty() {
+            return Ok(vec![]);
+        }
+        let part = texts.len().div_ceil(self.instances.len());
+        let parts: Vec<Result<Vec<Embedding>>> = std::thread::scope(|scope| {
+            let threads: Vec<_> = texts
+                .chunks(part)
+                .zip(&self.instances)
+                .map(|(texts, instance)| {
+                    scope.spawn(move || instance.lock().unwrap_or_else(|e| e.into_inner()).encode(texts))
+                })
+                .collect();
+            threads.into_iter().map(|thread| thread.join().expect("encoding thread panicked")).collect()
+        });
+
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for part in parts {
+            embeddings.extend(part?);
+        }
+        Ok(embeddings)
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn count_tokens(&self, text: &str) -> Result<Option<usize>> {
+        self.instances[0].lock().unwrap_or_else(|e| e.into_inner()).count_tokens(text)
+    }
+}
+
+/// 64 bit FNV-1a, stable across platforms and releases, unlike `DefaultHasher`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::normalize_all_inplace;
+
+    #[test]
+    fn test_hashing_embeddings_are_deterministic() {
+        let embedder = HashingEmbedder::new().with_dimension(64);
+        let embeddings = embedder.encode(&["I can't log in", "i CAN'T log in!", "", "?!"]).unwrap();
+        assert_eq!(64, embeddings[0].len());
+        assert_eq!(embeddings[0], embeddings[1]);
+        assert!(embeddings[2].iter().any(|v| *v != 0.0));
+        assert_eq!(embeddings[2], embeddings[3]);
+        assert_eq!(0xaf63_dc4c_8601_ec8c, fnv1a(b"a"));
+        assert_eq!("hashing-64", embedder.name());
+        assert_eq!(Some(4), embedder.count_tokens("I can't log").unwrap());
+    }
+
+    #[test]
+    fn test_shared_words_are_similar() {
+        let embeddings = HashingEmbedder::new()
+            .encode(&["reset my password", "how do I reset my password", "the weather is nice today"])
+            .unwrap();
+        let embeddings = normalize_all_inplace(embeddings);
+        let similarity = |a: usize, b: usize| embeddings[a].iter().zip(&embeddings[b]).map(|(x, y)| x * y).sum::<f32>();
+
+        assert!(similarity(0, 1) > 0.6);
+        assert!(similarity(0, 2) < 0.3);
+    }
+
+    #[test]
+    fn test_parallel_embeddings_keep_their_order() {
+        let texts: Vec<String> = (0..103).map(|i| format!("document number {}", i)).collect();
+        let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+        let instances: Vec<Box<dyn Embedder + Send>> = (0..4).map(|_| Box::new(HashingEmbedder::new()) as Box<dyn Embedder + Send>).collect();
+        let parallel = ParallelEmbedder::new(instances).unwrap();
+
+        assert_eq!(HashingEmbedder::new().encode(&texts).unwrap(), parallel.encode(&texts).unwrap());
+        assert_eq!(HashingEmbedder::new().encode(&texts[..2]).unwrap(), parallel.encode(&texts[..2]).unwrap());
+        assert!(parallel.encode(&[]).unwrap().is_empty());
+
+        assert!(ParallelEmbedder::new(vec![]).is_err());
+        let mixed: Vec<Box<dyn Embedder + Send>> = vec![Box::new(HashingEmbedder::new()), Box::new(HashingEmbedder::new().with_dimension(8))];
+        assert!(ParallelEmbedder::new(mixed).is_err());
+    }
+}
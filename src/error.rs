@@ -0,0 +1,96 @@
+use std::io;
+
+/// Everything that can go wrong reading, clustering and writing embeddings.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Opening, reading or writing a file
+    #[error("{path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+
+    /// A file that isn't in the format it should be. `input` is a path, or what was being read
+    /// when there isn't one
+    #[error("{input}: {message}")]
+    Parse { input: String, message: String },
+
+    /// Vectors that aren't all the same length
+    #[error("{input}: row {row} has {found} values, expected {expected}")]
+    DimensionMismatch {
+        input: String,
+        row: usize,
+        expected: usize,
+        found: usize,
+    },
+
+    /// Nothing to work with
+    #[error("{0}: no vectors")]
+    EmptyInput(String),
+
+    /// Loading or running the sentence embeddings model
+    #[error("sentence embeddings model: {0}")]
+    Model(String),
+
+    /// A parameter outside the range it makes sense in
+    #[error("invalid {name}: {message}")]
+    InvalidParameter { name: &'static str, message: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// An io error while working on `path`. `InvalidData` errors come from our own readers
+    /// rejecting a file, so they're parse errors.
+    pub fn io(path: &str, source: io::Error) -> Error {
+        match source.kind() {
+            io::ErrorKind::InvalidData => Error::parse(path, source),
+            _ => Error::Io { path: path.to_string(), source },
+        }
+    }
+
+    pub fn parse(input: &str, message: impl ToString) -> Error {
+        Error::Parse { input: input.to_string(), message: message.to_string() }
+    }
+
+    pub fn invalid_parameter(name: &'static str, message: impl ToString) -> Error {
+        Error::InvalidParameter { name, message: message.to_string() }
+    }
+
+    /// Process exit code for the CLI, distinct per kind of error. 1 is left for anything else,
+    /// and 2 is clap's code for bad arguments.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io { .. } => 3,
+            Error::Parse { .. } => 4,
+            Error::DimensionMismatch { .. } => 5,
+            Error::EmptyInput(_) => 6,
+            Error::Model(_) => 7,
+            Error::InvalidParameter { .. } => 8,
+        }
+    }
+}
+
+#[cfg(feature = "rust-bert")]
+impl From<rust_bert::RustBertError> for Error {
+    fn from(error: rust_bert::RustBertError) -> Error {
+        Error::Model(error.to_string())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_data_is_a_parse_error() {
+        let error = Error::io("v.npy", io::Error::new(io::ErrorKind::InvalidData, "not a npy file"));
+        assert_eq!("v.npy: not a npy file", error.to_string());
+        assert_eq!(4, error.exit_code());
+
+        let error = Error::io("v.npy", io::Error::new(io::ErrorKind::NotFound, "No such file"));
+        assert_eq!(3, error.exit_code());
+    }
+}
@@ -1,49 +1,466 @@
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, BufWriter, Read};
+use std::str::FromStr;
 
+use ndarray::{Array2, ArrayView2, ArrayViewMut2};
+use serde::de::{self, DeserializeSeed, SeqAccess, Visitor};
+
+use crate::binary::{self, VectorFile};
+use crate::cluster::Clusters;
+use crate::error::{Error, Result};
+use crate::embed::Embedder;
+use crate::npy::{self, NpyDtype};
 use crate::time_it;
+use crate::timer::Progress;
 
-pub fn load_text(filename: &str) -> (Vec<Vec<f32>>, Vec<String>) {
-    use rust_bert::pipelines::sentence_embeddings::builder::SentenceEmbeddingsBuilder;
-    use rust_bert::pipelines::sentence_embeddings::SentenceEmbeddingsModelType;
+/// Reads a file of text, one document per line, and encodes every line with `embedder`.
+/// Returns the embeddings and the lines, in the same order.
+pub fn load_text(filename: &str, embedder: &dyn Embedder, options: &EncodeOptions) -> Result<(Vec<Vec<f32>>, Vec<String>)> {
+    let lines = load_lines(filename)?;
+    let embeddings = encode_lines(&lines, embedder, options)?;
+    Ok((embeddings, lines))
+}
 
-    time_it!(
-        "reading lines of text",
-        let file = File::open(filename).unwrap();
-        let reader = BufReader::new(file);
-        let lines = reader.lines().map(|x| x.unwrap()).collect::<Vec<String>>();
-        println!("loaded {} lines", lines.len());
-    );
+/// Default number of documents encoded at a time.
+pub const BATCH_SIZE: usize = 1000;
+
+/// How `encode_lines` feeds documents to the embedder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeOptions {
+    batch_size: usize,
+    progress: bool,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions { batch_size: BATCH_SIZE, progress: false }
+    }
+}
+
+impl EncodeOptions {
+    pub fn new() -> EncodeOptions {
+        EncodeOptions::default()
+    }
 
+    /// Documents passed to `Embedder::encode` at a time. A `ParallelEmbedder` splits each batch
+    /// between its instances.
+    pub fn with_batch_size(mut self, batch_size: usize) -> EncodeOptions {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Reports rows encoded, rows per second and the time left on stderr after every batch.
+    pub fn with_progress(mut self, progress: bool) -> EncodeOptions {
+        self.progress = progress;
+        self
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    pub fn progress(&self) -> bool {
+        self.progress
+    }
+}
+
+/// Encodes documents with `embedder`, a batch at a time. The embeddings aren't normalized.
+pub fn encode_lines(lines: &[String], embedder: &dyn Embedder, options: &EncodeOptions) -> Result<Vec<Vec<f32>>> {
     time_it!(
-        "loading sentence_embeddings model",
-        // same as we use in arty
-        let model = SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllMiniLmL6V2).create_model().unwrap();
+        "sentence_embeddings",
+        let mut embeddings: Vec<Vec<f32>> = Vec::with_capacity(lines.len());
+        let mut progress = options.progress.then(|| Progress::start("encoding", lines.len()));
+        for chunk in lines.chunks(options.batch_size) {
+            let chunk: Vec<&str> = chunk.iter().map(String::as_str).collect();
+            embeddings.extend(embedder.encode(&chunk)?);
+            if let Some(progress) = &mut progress {
+                progress.add(chunk.len());
+            }
+        }
+        if let Some(progress) = progress {
+            progress.finish();
+        }
     );
 
+    Ok(embeddings)
+}
+
+/// Reads a file of text, one document per line.
+pub fn load_lines(filename: &str) -> Result<Vec<String>> {
     time_it!(
-        "sentence_embeddings",
-        let embeddings: Vec<Vec<f32>> = lines.chunks(1000).flat_map(|c|model.encode(c).unwrap()).collect();
+        "reading lines of text",
+        let reader = BufReader::new(open(filename)?);
+        let lines = reader.lines().collect::<io::Result<Vec<String>>>().map_err(|e| Error::io(filename, e))?;
+        println!("loaded {} lines", lines.len());
     );
+    Ok(lines)
+}
+
+fn open(filename: &str) -> Result<File> {
+    File::open(filename).map_err(|e| Error::io(filename, e))
+}
 
-    (embeddings, lines)
+fn create(filename: &str) -> Result<File> {
+    File::create(filename).map_err(|e| Error::io(filename, e))
 }
 
-pub fn dump_as_json<T>(filename: &str, data: &T)
+fn json_error(filename: &str, error: serde_json::Error) -> Error {
+    if error.is_io() {
+        Error::io(filename, error.into())
+    } else {
+        Error::parse(filename, error)
+    }
+}
+
+/// Writes `data` to `filename` as pretty printed json.
+pub fn dump_as_json<T>(filename: &str, data: &T) -> Result<()>
 where
     T: serde::ser::Serialize,
 {
     time_it!(
         "dumping json",
-        std::fs::write(filename, serde_json::to_string_pretty(&data).unwrap()).expect("Failed to write file");
+        let json = serde_json::to_string_pretty(&data).map_err(|e| Error::parse(filename, e))?;
+        std::fs::write(filename, json).map_err(|e| Error::io(filename, e))?;
     );
+    Ok(())
 }
 
-pub fn load_vectors_from_json(filename: &str) -> Vec<Vec<f32>> {
+/// Reads embeddings written by `dump_as_json`, a json array of arrays of floats.
+pub fn load_vectors_from_json(filename: &str) -> Result<Vec<Vec<f32>>> {
     time_it!(
         "reading vectors from json",
-        let buffered_reader = BufReader::new(File::open(filename).unwrap());
-        let embeddings = serde_json::from_reader(buffered_reader).expect("failed to parse json");
+        let buffered_reader = BufReader::new(open(filename)?);
+        let embeddings = serde_json::from_reader(buffered_reader).map_err(|e| json_error(filename, e))?;
+    );
+    Ok(embeddings)
+}
+
+/// Reads a json array of arrays of floats straight into a matrix, one row at a time, without
+/// holding the parsed rows and the matrix at once.
+///
+/// The file is read twice, first to count the rows so the matrix is allocated once at its
+/// final size. Rows that aren't the same length as the first row are an error naming the row.
+pub fn load_matrix_from_json(filename: &str) -> Result<Array2<f32>> {
+    time_it!(
+        "reading matrix from json",
+        let rows = count_json_rows(open(filename)?).map_err(|e| Error::io(filename, e))?;
+        let embeddings = read_json_matrix(filename, BufReader::new(open(filename)?), rows)?;
+    );
+    Ok(embeddings)
+}
+
+/// Counts the arrays opened directly inside the outer array, without parsing anything.
+fn count_json_rows<R: Read>(reader: R) -> io::Result<usize> {
+    let mut reader = BufReader::with_capacity(1 << 16, reader);
+    let mut depth = 0usize;
+    let mut rows = 0;
+
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(rows);
+        }
+        for byte in buffer {
+            match byte {
+                b'[' => {
+                    depth += 1;
+                    if depth == 2 {
+                        rows += 1;
+                    }
+                }
+                b']' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        let consumed = buffer.len();
+        reader.consume(consumed);
+    }
+}
+
+/// Parses a json array of arrays of floats into a matrix, reserving room for `rows` rows once
+/// the first row gives the dimension.
+fn read_json_matrix<R: Read>(filename: &str, reader: R, rows: usize) -> Result<Array2<f32>> {
+    let mut values = vec![];
+    let mut dimension = None;
+    let mut mismatch = None;
+
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let found = MatrixSeed { values: &mut values, dimension: &mut dimension, mismatch: &mut mismatch, rows }
+        .deserialize(&mut deserializer)
+        .and_then(|found| deserializer.end().map(|_| found));
+    let found = match (found, mismatch) {
+        (Err(_), Some((row, expected, found))) => {
+            return Err(Error::DimensionMismatch { input: filename.to_string(), row, expected, found });
+        }
+        (found, _) => found.map_err(|e| json_error(filename, e))?,
+    };
+
+    let shape = (found, dimension.unwrap_or(0));
+    Ok(Array2::from_shape_vec(shape, values).expect("every row checked against the first"))
+}
+
+/// Deserializes the outer array, appending every row to `values`, returns the number of rows.
+/// A row with a different length to the first stops it, and is recorded in `mismatch` as
+/// `(row, expected, found)`.
+struct MatrixSeed<'a> {
+    values: &'a mut Vec<f32>,
+    dimension: &'a mut Option<usize>,
+    mismatch: &'a mut Option<(usize, usize, usize)>,
+    rows: usize,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for MatrixSeed<'a> {
+    type Value = usize;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> std::result::Result<usize, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for MatrixSeed<'a> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of arrays of floats")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<usize, A::Error> {
+        let mut row = 0;
+        while let Some(len) = seq.next_element_seed(RowSeed { values: self.values })? {
+            match *self.dimension {
+                None => {
+                    *self.dimension = Some(len);
+                    self.values.reserve_exact(self.rows.saturating_sub(1) * len);
+                }
+                Some(dimension) if dimension != len => {
+                    *self.mismatch = Some((row, dimension, len));
+                    return Err(de::Error::custom(format!(
+                        "row {} has {} values, expected {} like the rows before it",
+                        row, len, dimension
+                    )));
+                }
+                Some(_) => {}
+            }
+            row += 1;
+        }
+        Ok(row)
+    }
+}
+
+/// Deserializes one row, appending its values to `values`, returns the number of values.
+struct RowSeed<'a> {
+    values: &'a mut Vec<f32>,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for RowSeed<'a> {
+    type Value = usize;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> std::result::Result<usize, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for RowSeed<'a> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of floats")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<usize, A::Error> {
+        let mut len = 0;
+        while let Some(value) = seq.next_element::<f32>()? {
+            self.values.push(value);
+            len += 1;
+        }
+        Ok(len)
+    }
+}
+
+/// File formats for matrices of floats, embeddings or t-SNE coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorFormat {
+    /// Pretty printed json array of arrays
+    Json,
+    /// See `binary`
+    Binary,
+    /// NumPy `.npy`, float32 or float16 when reading
+    Npy,
+    /// NumPy `.npz`, a zip of `.npy` files
+    Npz,
+}
+
+impl VectorFormat {
+    pub const NAMES: [&'static str; 4] = ["json", "binary", "npy", "npz"];
+
+    /// Recognises binary, npy and npz files by their magic bytes, anything else is json.
+    pub fn detect(filename: &str) -> io::Result<VectorFormat> {
+        let mut magic = Vec::with_capacity(8);
+        File::open(filename)?.take(8).read_to_end(&mut magic)?;
+
+        Ok(if magic.starts_with(binary::MAGIC) {
+            VectorFormat::Binary
+        } else if magic.starts_with(npy::MAGIC) {
+            VectorFormat::Npy
+        } else if magic.starts_with(npy::ZIP_MAGIC) {
+            VectorFormat::Npz
+        } else {
+            VectorFormat::Json
+        })
+    }
+}
+
+impl FromStr for VectorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<VectorFormat, String> {
+        match s {
+            "json" => Ok(VectorFormat::Json),
+            "binary" => Ok(VectorFormat::Binary),
+            "npy" => Ok(VectorFormat::Npy),
+            "npz" => Ok(VectorFormat::Npz),
+            _ => Err(format!("unknown format {}, expected one of {}", s, VectorFormat::NAMES.join(", "))),
+        }
+    }
+}
+
+/// Embeddings read from a vectors file, one per row.
+pub enum Vectors {
+    /// Parsed from json or numpy files
+    Owned(Array2<f32>),
+    /// Mapped from a binary file, see `binary`
+    Mapped(VectorFile),
+}
+
+impl Vectors {
+    pub fn view(&self) -> ArrayView2<'_, f32> {
+        match self {
+            Vectors::Owned(embeddings) => embeddings.view(),
+            Vectors::Mapped(file) => file.view(),
+        }
+    }
+
+    pub fn view_mut(&mut self) -> ArrayViewMut2<'_, f32> {
+        match self {
+            Vectors::Owned(embeddings) => embeddings.view_mut(),
+            Vectors::Mapped(file) => file.view_mut(),
+        }
+    }
+}
+
+/// Name of the embeddings in npz archives we write, and the array read from archives
+/// holding more than one.
+pub const NPZ_EMBEDDINGS: &str = "embeddings";
+
+/// Reads a file of embeddings, in any `VectorFormat`, detected from its magic bytes. Binary
+/// vector files are memory mapped.
+pub fn load_vectors(filename: &str) -> Result<Vectors> {
+    let vectors = match VectorFormat::detect(filename).map_err(|e| Error::io(filename, e))? {
+        VectorFormat::Binary => {
+            time_it!(
+                "mapping binary vectors",
+                let file = VectorFile::open(filename).map_err(|e| Error::io(filename, e))?;
+            );
+            Vectors::Mapped(file)
+        }
+        VectorFormat::Npy => {
+            time_it!(
+                "reading vectors from npy",
+                let mut reader = BufReader::new(open(filename)?);
+                let embeddings = npy::read_npy(&mut reader).map_err(|e| Error::io(filename, e))?;
+            );
+            Vectors::Owned(embeddings)
+        }
+        VectorFormat::Npz => {
+            time_it!(
+                "reading vectors from npz",
+                let reader = BufReader::new(open(filename)?);
+                let embeddings = npy::read_npz(reader, NPZ_EMBEDDINGS).map_err(|e| Error::io(filename, e))?;
+            );
+            Vectors::Owned(embeddings)
+        }
+        VectorFormat::Json => Vectors::Owned(load_matrix_from_json(filename)?),
+    };
+
+    if vectors.view().nrows() == 0 {
+        return Err(Error::EmptyInput(filename.to_string()));
+    }
+    Ok(vectors)
+}
+
+/// Writes a matrix, one vector per row, in `format`. `name` is the array's name in an npz
+/// archive, binary files are tagged with the name of the `model` that made the vectors.
+pub fn dump_vectors(filename: &str, name: &str, model: &str, vectors: ArrayView2<f32>, format: VectorFormat) -> Result<()> {
+    match format {
+        VectorFormat::Json => {
+            let rows: Vec<Vec<f32>> = vectors.rows().into_iter().map(|row| row.to_vec()).collect();
+            dump_as_json(filename, &rows)?;
+        }
+        VectorFormat::Binary => {
+            time_it!(
+                "dumping binary vectors",
+                binary::write(filename, vectors, model).map_err(|e| Error::io(filename, e))?;
+            );
+        }
+        VectorFormat::Npy => {
+            time_it!(
+                "dumping npy",
+                let mut writer = BufWriter::new(create(filename)?);
+                npy::write_npy(&mut writer, vectors, NpyDtype::F32).map_err(|e| Error::io(filename, e))?;
+            );
+        }
+        VectorFormat::Npz => {
+            time_it!(
+                "dumping npz",
+                let writer = BufWriter::new(create(filename)?);
+                npy::write_npz(writer, &[(name, vectors)], NpyDtype::F32).map_err(|e| Error::io(filename, e))?;
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Reads clusters written by `dump_as_json`, a json array of `[centroid, [members...]]`.
+pub fn load_clusters_from_json(filename: &str) -> Result<Clusters> {
+    time_it!(
+        "reading clusters from json",
+        let buffered_reader = BufReader::new(open(filename)?);
+        let clusters = serde_json::from_reader(buffered_reader).map_err(|e| json_error(filename, e))?;
     );
-    embeddings
+    Ok(clusters)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    fn read(json: &str) -> Result<Array2<f32>> {
+        read_json_matrix("test.json", json.as_bytes(), count_json_rows(json.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn test_it_can_count_json_rows() {
+        assert_eq!(3, count_json_rows("[[1.0, 2.0], [3.0, 4.0],\n [5, 6]]".as_bytes()).unwrap());
+        assert_eq!(0, count_json_rows("[]".as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn test_it_can_read_json_matrix() {
+        assert_eq!(array![[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]], read("[[1.0, 2.0], [3.0, 4.0],\n [5, 6]]").unwrap());
+        assert_eq!((0, 0), read("[]").unwrap().dim());
+        assert_eq!((2, 0), read("[[], []]").unwrap().dim());
+    }
+
+    #[test]
+    fn test_it_reports_rows_with_the_wrong_dimension() {
+        let error = read("[[1.0, 2.0], [3.0, 4.0], [5.0]]").unwrap_err();
+        assert_eq!("test.json: row 2 has 1 values, expected 2", error.to_string());
+        assert_eq!(5, error.exit_code());
+
+        assert!(matches!(read("[[1.0, 2.0], [3.0, \"a\"]]"), Err(Error::Parse { .. })));
+        assert!(matches!(read("[[1.0, 2.0]] ["), Err(Error::Parse { .. })));
+    }
 }
@@ -0,0 +1,340 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+
+use ndarray::prelude::*;
+use rayon::prelude::*;
+
+use crate::cluster::Index;
+
+/// Tuning for the HNSW index.
+///
+/// `m` is the number of links per node (twice that on the bottom layer), `ef_construction` and
+/// `ef_search` are how many candidates are tracked while inserting and searching, higher is
+/// slower but finds more of the true neighbours.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HnswParams {
+    pub m: usize,
+    pub ef_construction: usize,
+    pub ef_search: usize,
+    pub seed: u64,
+}
+
+impl Default for HnswParams {
+    fn default() -> HnswParams {
+        HnswParams { m: 16, ef_construction: 100, ef_search: 64, seed: 42 }
+    }
+}
+
+/// A node and its similarity to whatever is being searched for, ordered by similarity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Scored {
+    similarity: f32,
+    index: Index,
+}
+
+impl Eq for Scored {}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Scored) -> Ordering {
+        self.similarity
+            .total_cmp(&other.similarity)
+            .then(other.index.cmp(&self.index))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Scored) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Hierarchical Navigable Small World graph over normalized embeddings, for finding the
+/// neighbours of a vector without comparing it to every other vector.
+///
+/// Nodes are inserted in batches, each batch searches the graph built so far in parallel, then
+/// is linked in order. So the graph only depends on the input and the seed, not on threading.
+pub struct Hnsw<'a> {
+    embeddings: ArrayView2<'a, f32>,
+    params: HnswParams,
+    /// `links[node][level]` are the neighbours of `node` on `level`
+    links: Vec<Vec<Vec<Index>>>,
+    entry: Option<Index>,
+    max_level: usize,
+}
+
+/// Batches start small, so the first nodes link to each other properly, and grow with the graph.
+const MAX_INSERT_BATCH: usize = 1024;
+
+impl<'a> Hnsw<'a> {
+    pub fn build(embeddings: ArrayView2<'a, f32>, params: HnswParams) -> Hnsw<'a> {
+        let n = embeddings.nrows();
+        let levels = random_levels(n, params.m, params.seed);
+
+        let mut hnsw = Hnsw {
+            embeddings,
+            params,
+            links: levels.iter().map(|level| vec![vec![]; level + 1]).collect(),
+            entry: None,
+            max_level: 0,
+        };
+
+        let mut start = 0;
+        while start < n {
+            let end = n.min(start + (start / 8).clamp(1, MAX_INSERT_BATCH));
+            let candidates: Vec<Vec<Vec<Scored>>> = (start..end)
+                .into_par_iter()
+                .map(|node| hnsw.insert_candidates(node, levels[node]))
+                .collect();
+            for (node, candidates) in (start..end).zip(candidates) {
+                hnsw.link(node, levels[node], candidates, start);
+            }
+            start = end;
+        }
+
+        hnsw
+    }
+
+    pub fn len(&self) -> usize {
+        self.links.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty()
+    }
+
+    /// Indices of the nodes with a similarity to `query` above `threshold`, ascending.
+    ///
+    /// Searches with `ef_search` candidates, doubling it while every candidate found is above the
+    /// threshold, so large communities aren't cut off.
+    pub fn neighbours_above(&self, query: ArrayView1<f32>, threshold: f32) -> Vec<Index> {
+        let entry = match self.entry {
+            Some(entry) => entry,
+            None => return vec![],
+        };
+
+        let mut closest = self.score(query, entry);
+        for level in (1..=self.max_level).rev() {
+            closest = self.greedy_closest(query, closest, level);
+        }
+
+        let mut ef = self.params.ef_search.max(1);
+        loop {
+            let found = self.search_layer(query, closest, ef, 0);
+            if found.iter().any(|s| s.similarity <= threshold) || ef >= self.len() {
+                let mut above: Vec<Index> = found
+                    .into_iter()
+                    .filter(|s| s.similarity > threshold)
+                    .map(|s| s.index)
+                    .collect();
+                above.sort_unstable();
+                return above;
+            }
+            ef *= 2;
+        }
+    }
+
+    fn score(&self, query: ArrayView1<f32>, index: Index) -> Scored {
+        Scored { similarity: query.dot(&self.embeddings.row(index)), index }
+    }
+
+    fn max_links(&self, level: usize) -> usize {
+        if level == 0 { 2 * self.params.m } else { self.params.m }
+    }
+
+    /// Searches the graph as it is, for the closest nodes to `node` on each level it will be on.
+    fn insert_candidates(&self, node: Index, level: usize) -> Vec<Vec<Scored>> {
+        let mut candidates = vec![vec![]; level + 1];
+        let entry = match self.entry {
+            Some(entry) => entry,
+            None => return candidates,
+        };
+
+        let query = self.embeddings.row(node);
+        let mut closest = self.score(query, entry);
+        for l in (level + 1..=self.max_level).rev() {
+            closest = self.greedy_closest(query, closest, l);
+        }
+        for l in (0..=level.min(self.max_level)).rev() {
+            let found = self.search_layer(query, closest, self.params.ef_construction, l);
+            closest = found[0];
+            candidates[l] = found;
+        }
+
+        candidates
+    }
+
+    /// Links `node` into the graph, also considering the nodes inserted before it in its batch,
+    /// which weren't in the graph when its candidates were searched for.
+    fn link(&mut self, node: Index, level: usize, candidates: Vec<Vec<Scored>>, batch_start: Index) {
+        let embeddings = self.embeddings;
+        let query = embeddings.row(node);
+
+        for (l, mut candidates) in candidates.into_iter().enumerate() {
+            candidates.extend(
+                (batch_start..node)
+                    .filter(|other| self.links[*other].len() > l)
+                    .map(|other| self.score(query, other)),
+            );
+            candidates.sort_unstable_by(|a, b| b.cmp(a));
+            candidates.dedup_by_key(|s| s.index);
+
+            let neighbours = self.select_neighbours(candidates, self.max_links(l));
+            for neighbour in &neighbours {
+                self.links[*neighbour][l].push(node);
+                if self.links[*neighbour][l].len() > self.max_links(l) {
+                    self.shrink(*neighbour, l);
+                }
+            }
+            self.links[node][l] = neighbours;
+        }
+
+        if self.entry.is_none() || level > self.max_level {
+            self.entry = Some(node);
+            self.max_level = level;
+        }
+    }
+
+    fn shrink(&mut self, node: Index, level: usize) {
+        let query = self.embeddings.row(node);
+        let mut candidates: Vec<Scored> = self.links[node][level]
+            .iter()
+            .map(|other| self.score(query, *other))
+            .collect();
+        candidates.sort_unstable_by(|a, b| b.cmp(a));
+        self.links[node][level] = self.select_neighbours(candidates, self.max_links(level));
+    }
+
+    /// Picks up to `max` neighbours from candidates sorted closest first, preferring candidates
+    /// closer to the node than to any neighbour already picked, so links spread out in different
+    /// directions. Tops up with the closest of the rest.
+    fn select_neighbours(&self, candidates: Vec<Scored>, max: usize) -> Vec<Index> {
+        let mut selected: Vec<Index> = Vec::with_capacity(max);
+        let mut skipped: Vec<Index> = vec![];
+
+        for candidate in candidates {
+            if selected.len() >= max {
+                break;
+            }
+            let row = self.embeddings.row(candidate.index);
+            let diverse = selected
+                .iter()
+                .all(|s| row.dot(&self.embeddings.row(*s)) < candidate.similarity);
+            if diverse {
+                selected.push(candidate.index);
+            } else {
+                skipped.push(candidate.index);
+            }
+        }
+
+        let missing = max.saturating_sub(selected.len());
+        selected.extend(skipped.into_iter().take(missing));
+        selected
+    }
+
+    fn greedy_closest(&self, query: ArrayView1<f32>, mut closest: Scored, level: usize) -> Scored {
+        loop {
+            let next = self.links[closest.index][level]
+                .iter()
+                .map(|n| self.score(query, *n))
+                .max()
+                .filter(|n| *n > closest);
+            match next {
+                Some(next) => closest = next,
+                None => return closest,
+            }
+        }
+    }
+
+    /// The `ef` closest nodes found on `level` starting from `entry`, closest first.
+    fn search_layer(&self, query: ArrayView1<f32>, entry: Scored, ef: usize, level: usize) -> Vec<Scored> {
+        let mut visited: HashSet<Index> = HashSet::from([entry.index]);
+        let mut candidates: BinaryHeap<Scored> = BinaryHeap::from([entry]);
+        let mut found: BinaryHeap<Reverse<Scored>> = BinaryHeap::from([Reverse(entry)]);
+
+        while let Some(candidate) = candidates.pop() {
+            let furthest = found.peek().expect("found is never empty").0;
+            if candidate < furthest && found.len() >= ef {
+                break;
+            }
+
+            for neighbour in &self.links[candidate.index][level] {
+                if !visited.insert(*neighbour) {
+                    continue;
+                }
+                let neighbour = self.score(query, *neighbour);
+                let furthest = found.peek().expect("found is never empty").0;
+                if found.len() < ef || neighbour > furthest {
+                    candidates.push(neighbour);
+                    found.push(Reverse(neighbour));
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+
+        let mut found: Vec<Scored> = found.into_iter().map(|s| s.0).collect();
+        found.sort_unstable_by(|a, b| b.cmp(a));
+        found
+    }
+}
+
+/// Level of each node, exponentially fewer nodes on each level up, deterministic for a seed.
+fn random_levels(n: usize, m: usize, seed: u64) -> Vec<usize> {
+    let multiplier = 1.0 / (m.max(2) as f64).ln();
+    let mut state = seed.max(1);
+    (0..n)
+        .map(|_| {
+            // xorshift64*
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            let random = state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+            let uniform = (random as f64 + 1.0) / (1u64 << 53) as f64;
+            (-uniform.ln() * multiplier) as usize
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::{normalize_all_inplace, vectors_to_array};
+
+    #[test]
+    fn test_random_levels_decay() {
+        let levels = random_levels(10_000, 16, 1);
+        assert_eq!(levels, random_levels(10_000, 16, 1));
+        let level_0 = levels.iter().filter(|l| **l == 0).count();
+        let level_1 = levels.iter().filter(|l| **l == 1).count();
+        assert!(level_0 > 9_000);
+        assert!(level_1 > 300 && level_1 < 900);
+    }
+
+    #[test]
+    fn test_it_finds_neighbours_above_threshold() {
+        let embeddings = vectors_to_array(normalize_all_inplace(vec![
+            vec![1.0, 0.0, 0.0],
+            vec![0.9, 0.1, 0.0],
+            vec![0.0, 1.0, 0.0],
+            vec![0.0, 0.9, 0.1],
+            vec![0.0, 0.0, 1.0],
+        ])).unwrap();
+        let hnsw = Hnsw::build(embeddings.view(), HnswParams::default());
+
+        assert_eq!(5, hnsw.len());
+        assert_eq!(vec![0, 1], hnsw.neighbours_above(embeddings.row(0), 0.9));
+        assert_eq!(vec![2, 3], hnsw.neighbours_above(embeddings.row(3), 0.9));
+        assert_eq!(vec![4], hnsw.neighbours_above(embeddings.row(4), 0.9));
+        assert_eq!(vec![0, 1, 2, 3, 4], hnsw.neighbours_above(embeddings.row(4), -1.0));
+    }
+
+    #[test]
+    fn test_empty_index_finds_nothing() {
+        let embeddings = Array2::<f32>::zeros((0, 3));
+        let hnsw = Hnsw::build(embeddings.view(), HnswParams::default());
+        assert!(hnsw.is_empty());
+        assert_eq!(Vec::<Index>::new(), hnsw.neighbours_above(array![1.0, 0.0, 0.0].view(), 0.5));
+    }
+}
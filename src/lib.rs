@@ -0,0 +1,72 @@
+//! Sentence embedding clustering.
+//!
+//! Clusters documents into communities of similar sentences: every document whose embedding
+//! has enough neighbours above a cosine similarity threshold becomes a candidate community,
+//! and the largest non-overlapping communities are kept.
+//!
+//! ```
+//! use cluster::{vectors_to_array, normalize_all_inplace, Batched, ClusterParams, ClusteringAlgorithm};
+//!
+//! let embeddings = normalize_all_inplace(vec![vec![0.5, 0.5]; 10]);
+//! let embeddings = vectors_to_array(embeddings)?;
+//!
+//! let clusters = Batched.cluster(embeddings.view(), &ClusterParams::default());
+//! assert_eq!(vec![(0, (0..10).collect::<Vec<_>>())], clusters);
+//! # Ok::<(), cluster::Error>(())
+//! ```
+//!
+//! Embeddings can be produced from text with [`file::load_text`] and an [`Embedder`], either a
+//! sentence embeddings model loaded by [`ModelSource`] (the default `rust-bert` feature) or the
+//! model free [`HashingEmbedder`], or loaded from a previous run with [`file::load_vectors`].
+//! A [`CachedEmbedder`] only encodes texts it hasn't seen before. [`PhaticDetector`] filters out
+//! small talk. Anything that can fail returns a [`Result`], see
+//! [`Error`] for what can go wrong.
+
+pub mod binary;
+pub mod cache;
+pub mod cluster;
+pub mod documents;
+pub mod embed;
+pub mod error;
+pub mod file;
+pub mod hnsw;
+pub mod memory;
+pub mod model;
+pub mod npy;
+pub mod phatic;
+pub mod preprocess;
+pub mod report;
+pub mod timer;
+pub mod tsne;
+
+pub use crate::cluster::{
+    normalize_all_inplace,
+    normalize_rows_inplace,
+    update_clusters,
+    vectors_to_array,
+    Algorithm,
+    Ann,
+    Auto,
+    BatchPlan,
+    Batched,
+    BatchedParallel,
+    BatchedPrune,
+    ClusterParams,
+    ClusteringAlgorithm,
+    Clusters,
+    Community,
+    Embedding,
+    Full,
+    Index,
+    RowWise,
+    Symmetric,
+};
+pub use crate::cache::{CachedEmbedder, EmbeddingCache};
+pub use crate::documents::{Documents, TextFormat, TextInput};
+pub use crate::embed::{Embedder, HashingEmbedder, ParallelEmbedder};
+pub use crate::error::{Error, Result};
+pub use crate::hnsw::{Hnsw, HnswParams};
+pub use crate::model::{ModelSource, ModelType};
+pub use crate::phatic::{PhaticDetector, PhaticDetectorBuilder, PhaticRule, PhaticVerdict, WordCount};
+pub use crate::preprocess::{Preprocessed, Preprocessor, Strip};
+pub use crate::report::{describe_clusters, ClusterReport, Member};
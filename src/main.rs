@@ -1,16 +1,16 @@
-use clap::{arg, Command};
-use crate::phatic::PhaticDetectorBuilder;
+use clap::{arg, Arg, ArgAction, ArgMatches, Command};
+use clap::builder::PossibleValuesParser;
+use cluster::file::{EncodeOptions, VectorFormat};
+use cluster::{cache, file, memory, Error, time_it, tsne, Algorithm, Ann, BatchPlan, CachedEmbedder, ClusterParams, ClusteringAlgorithm, Clusters, Embedder, HnswParams, Index, ModelSource, ModelType, ParallelEmbedder, PhaticDetectorBuilder, PhaticVerdict, Preprocessed, Preprocessor, Strip, TextFormat, TextInput, WordCount};
+use cluster::cache::{PruneOptions, CACHE_DIR_ENV};
+use cluster::model::MODEL_DIR_ENV;
+use ndarray::{Array2, ArrayView2, Axis};
+use serde::Serialize;
 
 #[cfg(feature = "dhat-heap")]
 #[global_allocator]
 static ALLOC: dhat::Alloc = dhat::Alloc;
 
-mod cluster;
-mod file;
-mod phatic;
-mod timer;
-mod tsne;
-
 fn cli() -> Command {
     Command::new("cluster")
         .about("testing clustering")
@@ -18,48 +18,339 @@ fn cli() -> Command {
         .subcommand(
             Command::new("vectors")
                 .about("Read a file of text, dump a file of vectors")
-                .arg(arg!(<TEXT_FILE> "input file"))
-                .arg(arg!(<VECTOR_FILE> "outfile file")),
+                .arg(arg!(<TEXT_FILE> "input file, lines of text, csv or jsonl"))
+                .arg(arg!(<VECTOR_FILE> "outfile file"))
+                .args(text_input_args())
+                .args(preprocess_args())
+                .arg(arg!(--lines <FILE> "also dump the input documents of each vector, counting from 0, for when preprocessing leaves some out"))
+                .args(model_args())
+                .arg(batch_size_arg())
+                .arg(
+                    arg!(--format <FORMAT> "json: array of arrays of floats\nbinary: compact, memory mapped when read back\nnpy, npz: numpy float32")
+                        .value_parser(PossibleValuesParser::new(VectorFormat::NAMES))
+                        .default_value("json"),
+                ),
         )
         .subcommand(
             Command::new("phatic")
                 .about("Is a string phatic?")
                 .arg(arg!(<INPUT> "input string"))
-                .arg(arg!(--similarity <SIMILARITY> "similarity"))
-                .arg(arg!(--prevector "give vector to phatic detector?")),
+                .arg(arg!(--similarity <SIMILARITY> "similarity").required(true).value_parser(clap::value_parser!(f32)))
+                .arg(arg!(--prevector "give vector to phatic detector?"))
+                .arg(arg!(--explain "say which rule decided, and how similar the string is to the nearest example"))
+                .args(examples_args())
+                .args(word_limit_args())
+                .args(model_args()),
         )
         .subcommand(
-            Command::new("cluster-ndarray")
-                .about("Read a file of vectors, dump a file of clusters\nUses N^2 memory, optimized")
-                .arg(arg!(<VECTOR_FILE> "input file"))
-                .arg(arg!(<CLUSTER_FILE> "outfile file")),
+            Command::new("phatic-batch")
+                .about("Read a file of text, dump whether each document is phatic")
+                .arg(arg!(<TEXT_FILE> "input file, lines of text, csv or jsonl"))
+                .arg(arg!(<OUTPUT_FILE> "outfile file, a verdict per document"))
+                .arg(arg!(--similarity <SIMILARITY> "similarity").required(true).value_parser(clap::value_parser!(f32)))
+                .args(text_input_args())
+                .args(examples_args())
+                .args(word_limit_args())
+                .args(model_args())
+                .arg(batch_size_arg()),
         )
         .subcommand(
-            Command::new("cluster-ndarray2")
-                .about("Read a file of vectors, dump a file of clusters\nUses low memory, optimized")
+            Command::new("cluster")
+                .about("Read a file of vectors, dump a file of clusters")
                 .arg(arg!(<VECTOR_FILE> "input file"))
-                .arg(arg!(<CLUSTER_FILE> "outfile file")),
+                .arg(arg!(<CLUSTER_FILE> "outfile file"))
+                .arg(algorithm_arg())
+                .args(cluster_args())
+                .args(output_args())
+                .args(text_input_args())
+                .args(ann_args()),
         )
         .subcommand(
-            Command::new("cluster-ndarray3")
-                .about("Read a file of vectors, dump a file of clusters\nUses low memory, optimized")
-                .arg(arg!(<VECTOR_FILE> "input file"))
-                .arg(arg!(<CLUSTER_FILE> "outfile file")),
+            Command::new("cluster-update")
+                .about("Add new vectors to a previous clustering, without reclustering everything")
+                .arg(arg!(<VECTOR_FILE> "vectors the clusters were made from"))
+                .arg(arg!(<CLUSTER_FILE> "clusters made from VECTOR_FILE"))
+                .arg(arg!(<NEW_VECTOR_FILE> "new vectors, numbered after the ones in VECTOR_FILE"))
+                .arg(arg!(<OUTPUT_FILE> "outfile file"))
+                .args(cluster_args())
+                .args(output_args())
+                .args(text_input_args()),
         )
         .subcommand(
-            Command::new("cluster-ndarray4")
-                .about("Read a file of vectors, dump a file of clusters\nUses low memory, unique otg")
-                .arg(arg!(<VECTOR_FILE> "input file"))
-                .arg(arg!(<CLUSTER_FILE> "outfile file")),
+            Command::new("pipeline")
+                .about("Read a file of text, dump a file of clusters with their text, all in one go")
+                .arg(arg!(<TEXT_FILE> "input file, lines of text, csv or jsonl"))
+                .arg(arg!(<CLUSTER_FILE> "outfile file"))
+                .args(text_input_args())
+                .args(preprocess_args())
+                .arg(
+                    arg!(--phatic <SIMILARITY> "leave out phatic lines, more similar than this to a phatic example")
+                        .value_parser(clap::value_parser!(f32)),
+                )
+                .args(examples_args())
+                .args(word_limit_args())
+                .args(model_args())
+                .arg(batch_size_arg())
+                .arg(arg!(--vectors <VECTOR_FILE> "also dump the vectors of every line"))
+                .arg(
+                    arg!(--format <FORMAT> "format of --vectors")
+                        .value_parser(PossibleValuesParser::new(VectorFormat::NAMES))
+                        .default_value("json"),
+                )
+                .arg(algorithm_arg())
+                .args(cluster_args())
+                .args(ann_args()),
+        )
+        .subcommand(
+            Command::new("cache")
+                .about("Look after the embedding cache")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("stats")
+                        .about("Entries, size and last use of each model's cache")
+                        .arg(cache_dir_arg().required(true)),
+                )
+                .subcommand(
+                    Command::new("prune")
+                        .about("Drop old and duplicate entries from each model's cache")
+                        .arg(cache_dir_arg().required(true))
+                        .arg(
+                            arg!(--"older-than" <DAYS> "drop entries not used for this many days")
+                                .value_parser(clap::value_parser!(u64)),
+                        )
+                        .arg(
+                            arg!(--"max-entries" <ENTRIES> "keep at most this many of the most recently used entries")
+                                .value_parser(clap::value_parser!(usize)),
+                        )
+                        .arg(arg!(--model <MODEL> "only prune the cache of this model")),
+                ),
         )
+        .subcommand(legacy_cluster_command("cluster-ndarray", Algorithm::Full))
+        .subcommand(legacy_cluster_command("cluster-ndarray2", Algorithm::RowWise))
+        .subcommand(legacy_cluster_command("cluster-ndarray3", Algorithm::Batched))
+        .subcommand(legacy_cluster_command("cluster-ndarray4", Algorithm::BatchedPrune))
         .subcommand(
             Command::new("tsne")
                 .about("Do a clustering, and use tsne to reduce dimensions")
                 .arg(arg!(<VECTOR_FILE> "input file"))
                 .arg(arg!(<TSNE_FILE> "output file"))
+                .arg(
+                    arg!(--format <FORMAT> "json: array of [x, y] pairs\nnpy, npz: numpy float32, N x 2")
+                        .value_parser(["json", "npy", "npz"])
+                        .default_value("json"),
+                )
+                .args(cluster_args())
         )
 }
 
+/// Subcommands from before `cluster --algorithm`, kept so existing scripts still work.
+fn legacy_cluster_command(name: &'static str, algorithm: Algorithm) -> Command {
+    Command::new(name)
+        .about(format!("Same as cluster --algorithm {}", algorithm))
+        .hide(true)
+        .arg(arg!(<VECTOR_FILE> "input file"))
+        .arg(arg!(<CLUSTER_FILE> "outfile file"))
+        .args(cluster_args())
+        .args(output_args())
+        .args(text_input_args())
+}
+
+fn algorithm_arg() -> Arg {
+    arg!(--algorithm <ALGORITHM> "full: N^2 memory\nrowwise: lowest memory, slowest\nbatched: low memory\nbatched-parallel: batched, several batches at once\nbatched-prune: low memory, unique otg\nsymmetric: blocked, half the multiplies\nann: approximate, HNSW index, for millions of vectors\nauto: pick from N and available memory")
+        .value_parser(PossibleValuesParser::new(Algorithm::NAMES))
+        .default_value("auto")
+}
+
+/// The clustering threshold options shared by every subcommand that clusters.
+fn cluster_args() -> [Arg; 4] {
+    [
+        arg!(--"min-similarity" <MIN_SIMILARITY> "cosine similarity a document must exceed to join a community [default: 0.70]")
+            .value_parser(parse_min_similarity),
+        arg!(--"min-cluster-size" <MIN_CLUSTER_SIZE> "a community must have more than this many members [default: 5]")
+            .value_parser(clap::value_parser!(usize)),
+        arg!(--"max-memory" <MAX_MEMORY> "memory budget for the batched algorithms, e.g. 512MB, sets the batch size")
+            .value_parser(memory::parse_size),
+        arg!(--"chunks-in-flight" <CHUNKS> "batches batched-parallel works on at once [default: number of cpus]")
+            .value_parser(clap::value_parser!(u64).range(1..)),
+    ]
+}
+
+/// How clusters are written, shared by every subcommand that writes clusters.
+fn output_args() -> [Arg; 2] {
+    [
+        arg!(--"output-format" <FORMAT> "indices: [centroid, [members...]]\nrich: centroid and members with their text and similarity to the centroid [default: rich with --input-text, otherwise indices]")
+            .value_parser(["indices", "rich"]),
+        arg!(--"input-text" <TEXT_FILE> "text the vectors were made from, a document per vector, attached to rich output"),
+    ]
+}
+
+/// Where to find documents in a text file.
+fn text_input_args() -> [Arg; 3] {
+    [
+        arg!(--"input-format" <FORMAT> "lines: a document per line\ncsv: with a header row\njsonl: a json object per line\n[default: from the extension, .csv, .jsonl or .ndjson, otherwise lines]")
+            .value_parser(PossibleValuesParser::new(TextFormat::NAMES)),
+        arg!(--"text-column" <COLUMN> "csv column or jsonl field with the text, a name, or a csv column number counting from 1 [default: text]")
+            .visible_alias("text-field"),
+        arg!(--"id-column" <COLUMN> "csv column or jsonl field with the document id, written with the clusters")
+            .visible_alias("id-field"),
+    ]
+}
+
+/// How documents are cleaned up before they're embedded, nothing by default.
+fn preprocess_args() -> [Arg; 5] {
+    [
+        arg!(--strip <WHAT> "take these out of the text before embedding, comma separated")
+            .value_parser(PossibleValuesParser::new(Strip::NAMES))
+            .value_delimiter(',')
+            .action(ArgAction::Append),
+        arg!(--lowercase "lowercase the text before embedding"),
+        arg!(--dedup "embed each distinct text once, its other documents are listed as duplicates"),
+        arg!(--"min-tokens" <TOKENS> "leave out documents with fewer whitespace separated tokens, 1 leaves out empty ones")
+            .value_parser(clap::value_parser!(usize)),
+        arg!(--"max-tokens" <TOKENS> "leave out documents with more whitespace separated tokens")
+            .value_parser(clap::value_parser!(usize)),
+    ]
+}
+
+/// What phatic detection compares text to.
+fn examples_args() -> [Arg; 3] {
+    [
+        arg!(--examples <FILE> "phatic examples, one per line, instead of the built in english chat ones"),
+        arg!(--"negative-examples" <FILE> "examples that aren't phatic, one per line, text closer to one of these than to any phatic example isn't phatic"),
+        arg!(--"examples-embeddings" <FILE> "save the example embeddings here and read them back next time, until the model or the examples change"),
+    ]
+}
+
+/// When phatic detection decides by the word count alone.
+fn word_limit_args() -> [Arg; 3] {
+    [
+        arg!(--"min-words" <WORDS> "text with fewer words is phatic without comparing it to the examples, none to always compare [default: 4]")
+            .value_parser(parse_word_limit),
+        arg!(--"max-words" <WORDS> "text with more words isn't phatic, without comparing it to the examples, none to always compare [default: 14]")
+            .value_parser(parse_word_limit),
+        arg!(--"word-count" <COUNT> "whitespace: count words between whitespace\ntokenizer: count the model's tokens, for languages without spaces")
+            .value_parser(PossibleValuesParser::new(WordCount::NAMES))
+            .default_value("whitespace"),
+    ]
+}
+
+fn parse_word_limit(value: &str) -> Result<Option<usize>, String> {
+    match value {
+        "none" => Ok(None),
+        _ => value.parse::<usize>().map(Some).map_err(|_| format!("{} isn't a number of words or none", value)),
+    }
+}
+
+/// Which sentence embeddings model to encode text with, how many copies of it to run, and where
+/// to cache what it encodes.
+fn model_args() -> [Arg; 4] {
+    [
+        arg!(--model <MODEL> "sentence embeddings model to download")
+            .value_parser(PossibleValuesParser::new(ModelType::NAMES))
+            .default_value(ModelType::default().name()),
+        arg!(--"model-dir" <DIR> "load the model from a local directory instead of downloading it, for hosts without internet access")
+            .env(MODEL_DIR_ENV),
+        arg!(--"model-instances" <INSTANCES> "load the model this many times, and encode parts of each batch on separate threads")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("1"),
+        cache_dir_arg(),
+    ]
+}
+
+fn batch_size_arg() -> Arg {
+    arg!(--"batch-size" <ROWS> "documents encoded at a time, by each model instance [default: 1000]")
+        .value_parser(clap::value_parser!(u64).range(1..))
+}
+
+fn cache_dir_arg() -> Arg {
+    arg!(--"cache-dir" <DIR> "embedding cache, only texts missing from it are encoded, and they're added to it")
+        .env(CACHE_DIR_ENV)
+}
+
+/// HNSW tuning for `--algorithm ann`.
+fn ann_args() -> [Arg; 4] {
+    [
+        arg!(--"ann-m" <M> "ann: links per node in the index [default: 16]")
+            .value_parser(clap::value_parser!(u64).range(2..)),
+        arg!(--"ann-ef-construction" <EF> "ann: candidates tracked while building the index [default: 100]")
+            .value_parser(clap::value_parser!(u64).range(1..)),
+        arg!(--"ann-ef" <EF> "ann: candidates tracked while searching, doubled while they're all neighbours [default: 64]")
+            .value_parser(clap::value_parser!(u64).range(1..)),
+        arg!(--"recall-sample" <ROWS> "ann: rows to check against exact scores, 0 to skip [default: 1000]")
+            .value_parser(clap::value_parser!(usize)),
+    ]
+}
+
+fn parse_min_similarity(value: &str) -> Result<f32, String> {
+    let similarity = value.parse::<f32>().map_err(|e| e.to_string())?;
+    if !(0.0..1.0).contains(&similarity) {
+        return Err(format!("{} is not in the range 0.0 <= similarity < 1.0", similarity));
+    }
+    Ok(similarity)
+}
+
+fn cluster_params(matches: &ArgMatches) -> ClusterParams {
+    let mut params = ClusterParams::default();
+    if let Some(min_similarity) = matches.get_one::<f32>("min-similarity") {
+        params = params.with_min_similarity(*min_similarity);
+    }
+    if let Some(min_cluster_size) = matches.get_one::<usize>("min-cluster-size") {
+        params = params.with_min_cluster_size(*min_cluster_size);
+    }
+    if let Some(max_memory) = matches.get_one::<usize>("max-memory") {
+        params = params.with_max_memory(*max_memory);
+    }
+    if let Some(chunks_in_flight) = matches.get_one::<u64>("chunks-in-flight") {
+        params = params.with_chunks_in_flight(*chunks_in_flight as usize);
+    }
+    params
+}
+
+fn text_input(matches: &ArgMatches) -> TextInput {
+    let mut input = TextInput::new();
+    if let Some(format) = matches.get_one::<String>("input-format") {
+        input = input.with_format(format.parse().expect("format to be one of the possible values"));
+    }
+    if let Some(text_column) = matches.get_one::<String>("text-column") {
+        input = input.with_text_field(text_column);
+    }
+    if let Some(id_column) = matches.get_one::<String>("id-column") {
+        input = input.with_id_field(id_column);
+    }
+    input
+}
+
+fn ann(matches: &ArgMatches) -> Ann {
+    let mut hnsw = HnswParams::default();
+    if let Some(m) = matches.get_one::<u64>("ann-m") {
+        hnsw.m = *m as usize;
+    }
+    if let Some(ef_construction) = matches.get_one::<u64>("ann-ef-construction") {
+        hnsw.ef_construction = *ef_construction as usize;
+    }
+    if let Some(ef_search) = matches.get_one::<u64>("ann-ef") {
+        hnsw.ef_search = *ef_search as usize;
+    }
+    let mut ann = Ann::default().with_hnsw_params(hnsw);
+    if let Some(recall_sample) = matches.get_one::<usize>("recall-sample") {
+        ann = ann.with_recall_sample(*recall_sample);
+    }
+    ann
+}
+
+fn check_memory_budget(params: &ClusterParams, embeddings: ArrayView2<f32>) -> cluster::Result<()> {
+    if let Some(max_memory) = params.max_memory {
+        let min_memory = BatchPlan::min_memory(embeddings.nrows(), embeddings.ncols());
+        if max_memory < min_memory {
+            return Err(Error::invalid_parameter(
+                "max-memory",
+                format!("too low, clustering {} vectors needs at least {} bytes", embeddings.nrows(), min_memory),
+            ));
+        }
+    }
+    Ok(())
+}
+
 macro_rules! get_arg {
     ($matches:expr, $id:literal) => {
         $matches
@@ -69,130 +360,402 @@ macro_rules! get_arg {
     };
 }
 
+fn algorithm(matches: &ArgMatches) -> Algorithm {
+    get_arg!(matches, "algorithm")
+        .parse::<Algorithm>()
+        .expect("algorithm to be one of the possible values")
+}
+
+fn model_source(matches: &ArgMatches) -> ModelSource {
+    let model_type = get_arg!(matches, "model")
+        .parse::<ModelType>()
+        .expect("model to be one of the possible values");
+    let model = ModelSource::new().with_model_type(model_type);
+    match matches.get_one::<String>("model-dir") {
+        Some(model_dir) => model.with_model_dir(model_dir),
+        None => model,
+    }
+}
+
+/// Loads the model, as many times as `--model-instances`, behind the embedding cache if there is one.
+fn embedder(matches: &ArgMatches) -> cluster::Result<Box<dyn Embedder>> {
+    let source = model_source(matches);
+    let embedder: Box<dyn Embedder> = match *matches.get_one::<u64>("model-instances").expect("model-instances has a default") {
+        1 => source.load()?,
+        instances => Box::new(ParallelEmbedder::new((0..instances).map(|_| source.load()).collect::<cluster::Result<_>>()?)?),
+    };
+    match matches.get_one::<String>("cache-dir") {
+        Some(dir) => Ok(Box::new(CachedEmbedder::new(embedder, dir.as_ref())?)),
+        None => Ok(embedder),
+    }
+}
+
+fn preprocessor(matches: &ArgMatches) -> Preprocessor {
+    let mut preprocessor = Preprocessor::new()
+        .with_lowercase(matches.get_flag("lowercase"))
+        .with_dedup(matches.get_flag("dedup"))
+        .with_min_tokens(matches.get_one::<usize>("min-tokens").copied().unwrap_or(0));
+    for strip in matches.get_many::<String>("strip").into_iter().flatten() {
+        preprocessor = preprocessor.with_strip(strip.parse::<Strip>().expect("strip to be one of the possible values"));
+    }
+    match matches.get_one::<usize>("max-tokens") {
+        Some(max_tokens) => preprocessor.with_max_tokens(*max_tokens),
+        None => preprocessor,
+    }
+}
+
+/// A phatic detector with `--examples`, `--negative-examples`, `--examples-embeddings` and the
+/// word limits, if they were given.
+fn phatic_detector(matches: &ArgMatches, similarity: f32, embedder: Box<dyn Embedder>) -> PhaticDetectorBuilder {
+    let mut builder = PhaticDetectorBuilder::new()
+        .with_similarity_threshold(similarity)
+        .with_embedder(embedder);
+    if let Some(examples) = matches.get_one::<String>("examples") {
+        builder = builder.with_examples_file(examples);
+    }
+    if let Some(negatives) = matches.get_one::<String>("negative-examples") {
+        builder = builder.with_negative_examples_file(negatives);
+    }
+    if let Some(embeddings) = matches.get_one::<String>("examples-embeddings") {
+        builder = builder.with_embeddings_file(embeddings);
+    }
+    if let Some(min_words) = matches.get_one::<Option<usize>>("min-words") {
+        builder = builder.with_min_words(*min_words);
+    }
+    if let Some(max_words) = matches.get_one::<Option<usize>>("max-words") {
+        builder = builder.with_max_words(*max_words);
+    }
+    let word_count = get_arg!(matches, "word-count").parse::<WordCount>().expect("word-count to be one of the possible values");
+    builder.with_word_count(word_count)
+}
+
+/// Preprocesses documents, saying how many were left out.
+fn preprocess(matches: &ArgMatches, texts: &[String]) -> Preprocessed {
+    time_it!(
+        "preprocessing",
+        let preprocessed = preprocessor(matches).apply(texts);
+        println!(
+            "{} rows to embed, left out {} documents for their length and {} duplicates",
+            preprocessed.len(),
+            preprocessed.dropped.len(),
+            preprocessed.duplicates()
+        );
+    );
+    preprocessed
+}
+
+/// Batches of `--batch-size` for each model instance, with progress on stderr.
+fn encode_options(matches: &ArgMatches) -> EncodeOptions {
+    let batch_size = matches.get_one::<u64>("batch-size").map_or(file::BATCH_SIZE, |rows| *rows as usize);
+    let instances = *matches.get_one::<u64>("model-instances").expect("model-instances has a default");
+    EncodeOptions::new().with_batch_size(batch_size.saturating_mul(instances as usize)).with_progress(true)
+}
+
+fn vector_format(matches: &ArgMatches) -> VectorFormat {
+    get_arg!(matches, "format")
+        .parse::<VectorFormat>()
+        .expect("format to be one of the possible values")
+}
+
+/// Reads a vectors file in any format, normalizing the embeddings in place.
+fn load_embeddings(filename: &str) -> cluster::Result<file::Vectors> {
+    let mut vectors = file::load_vectors(filename)?;
+    cluster::normalize_rows_inplace(vectors.view_mut());
+    Ok(vectors)
+}
+
+/// Whether to write a `ClusterReport` per cluster rather than bare indices.
+fn rich_output(submatch: &ArgMatches) -> bool {
+    match submatch.get_one::<String>("output-format") {
+        Some(format) => format == "rich",
+        None => submatch.contains_id("input-text"),
+    }
+}
+
+/// Writes clusters made from `embeddings` with their similarities, and the documents from
+/// `--input-text`.
+fn dump_report(submatch: &ArgMatches, output: &str, clusters: &Clusters, embeddings: ArrayView2<f32>) -> cluster::Result<()> {
+    let documents = submatch
+        .get_one::<String>("input-text")
+        .map(|filename| text_input(submatch).load(filename))
+        .transpose()?;
+    let reports = cluster::describe_clusters(clusters, embeddings, documents.as_ref())?;
+    file::dump_as_json(output, &reports)
+}
+
+/// `algorithm`, with the HNSW tuning from the command line for `ann`.
+fn clustering_algorithm(submatch: &ArgMatches, algorithm: Algorithm) -> Box<dyn ClusteringAlgorithm> {
+    match algorithm {
+        Algorithm::Ann => Box::new(ann(submatch)),
+        algorithm => algorithm.build(),
+    }
+}
+
+fn cluster_file(submatch: &ArgMatches, algorithm: Algorithm) -> cluster::Result<()> {
+    let input = get_arg!(submatch, "VECTOR_FILE");
+    let output = get_arg!(submatch, "CLUSTER_FILE");
+
+    let params = cluster_params(submatch);
+
+    let vectors = load_embeddings(input)?;
+    let embeddings = vectors.view();
+    check_memory_budget(&params, embeddings)?;
+
+    let algorithm = clustering_algorithm(submatch, algorithm);
+    time_it!(
+        "main_cluster",
+        let clusters = algorithm.cluster(embeddings, &params);
+    );
+    if rich_output(submatch) {
+        dump_report(submatch, output, &clusters, embeddings)
+    } else {
+        file::dump_as_json(output, &clusters)
+    }
+}
+
+/// Preprocesses and embeds every document in a text file, optionally leaves out the phatic
+/// ones, clusters the rest and writes a `ClusterReport` per cluster. Indices count documents in
+/// the file from 0, whether or not preprocessing or phatic detection left some out.
+fn pipeline(submatch: &ArgMatches) -> cluster::Result<()> {
+    let input = get_arg!(submatch, "TEXT_FILE");
+    let output = get_arg!(submatch, "CLUSTER_FILE");
+
+    let params = cluster_params(submatch);
+    let algorithm = clustering_algorithm(submatch, algorithm(submatch));
+
+    let documents = text_input(submatch).load(input)?;
+    let preprocessed = preprocess(submatch, &documents.texts);
+    if preprocessed.is_empty() {
+        return Err(Error::EmptyInput(input.to_string()));
+    }
+    let embedder = embedder(submatch)?;
+    let mut embeddings = cluster::vectors_to_array(file::encode_lines(&preprocessed.texts, embedder.as_ref(), &encode_options(submatch))?)?;
+    if let Some(vectors) = submatch.get_one::<String>("vectors") {
+        file::dump_vectors(vectors, file::NPZ_EMBEDDINGS, &embedder.name(), embeddings.view(), vector_format(submatch))?;
+    }
+    cluster::normalize_rows_inplace(embeddings.view_mut());
+
+    let cluster = |embeddings: ArrayView2<f32>| -> cluster::Result<Clusters> {
+        if embeddings.nrows() == 0 {
+            return Ok(vec![]);
+        }
+        check_memory_budget(&params, embeddings)?;
+        time_it!(
+            "main_cluster",
+            let clusters = algorithm.cluster(embeddings, &params);
+        );
+        Ok(clusters)
+    };
+
+    let clusters = match submatch.get_one::<f32>("phatic") {
+        Some(similarity) => {
+            let detector = phatic_detector(submatch, *similarity, embedder).build()?;
+            time_it!(
+                "phatic filter",
+                let verdicts = detector.classify_embedded(&preprocessed.texts, embeddings.view())?;
+                let kept: Vec<Index> = (0..verdicts.len()).filter(|idx| !verdicts[*idx].phatic).collect();
+            );
+            println!("left out {} phatic rows", preprocessed.len() - kept.len());
+
+            // cluster the rest, then turn their rows back into preprocessed rows
+            let clusters = cluster(embeddings.select(Axis(0), &kept).view())?;
+            clusters
+                .into_iter()
+                .map(|(centroid, members)| (kept[centroid], members.into_iter().map(|idx| kept[idx]).collect()))
+                .collect()
+        }
+        None => cluster(embeddings.view())?,
+    };
+
+    let reports = cluster::describe_clusters(&clusters, embeddings.view(), None)?;
+    file::dump_as_json(output, &preprocessed.trace_reports(reports, &documents))
+}
+
+fn print_cache_stats(stats: &cache::CacheStats) {
+    let date = |seconds: u64| {
+        chrono::NaiveDateTime::from_timestamp_opt(seconds as i64, 0).map_or_else(|| seconds.to_string(), |date| date.to_string())
+    };
+    print!("{}: {} entries of dimension {}, {} bytes", stats.model, stats.entries, stats.dimension, stats.bytes);
+    match stats.last_used {
+        Some((oldest, newest)) => println!(", last used {} to {}", date(oldest), date(newest)),
+        None => println!(),
+    }
+}
+
+/// A line of `phatic-batch` output.
+#[derive(Serialize)]
+struct DocumentVerdict<'a> {
+    index: Index,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+    #[serde(flatten)]
+    verdict: PhaticVerdict,
+}
+
 fn main() {
     #[cfg(feature = "dhat-heap")]
         let _profiler = dhat::Profiler::new_heap();
 
-    let matches = cli().get_matches();
+    if let Err(e) = run(cli().get_matches()) {
+        eprintln!("error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
 
+fn run(matches: ArgMatches) -> cluster::Result<()> {
     match matches.subcommand() {
         Some(("vectors", submatch)) => {
             let input = get_arg!(submatch, "TEXT_FILE");
             let output = get_arg!(submatch, "VECTOR_FILE");
 
-            let (e, _) = file::load_text(input);
-            file::dump_as_json(output, &e);
+            let documents = text_input(submatch).load(input)?;
+            let preprocessed = preprocess(submatch, &documents.texts);
+            if let Some(lines) = submatch.get_one::<String>("lines") {
+                file::dump_as_json(lines, &preprocessed.lines)?;
+            }
+            let embedder = embedder(submatch)?;
+            let e = file::encode_lines(&preprocessed.texts, embedder.as_ref(), &encode_options(submatch))?;
+            match vector_format(submatch) {
+                VectorFormat::Json => file::dump_as_json(output, &e),
+                format => file::dump_vectors(output, file::NPZ_EMBEDDINGS, &embedder.name(), cluster::vectors_to_array(e)?.view(), format),
+            }
         }
 
         Some(("phatic", submatch)) => {
             let input = get_arg!(submatch, "INPUT");
 
-            let similarity = submatch.get_one::<String>("similarity").expect("expected similarity");
-            let similarity = similarity.parse::<f32>().expect("Invalid float");
-            if similarity > 0.999 {
-                panic!("Similarity too high");
-            }
-            if similarity < 0.001 {
-                panic!("Similarity too low");
-            }
-
-            let p = PhaticDetectorBuilder::new()
-                .with_similarity_threshold(similarity)
-                .build()
-                .expect("Expect to construct phatic detector");
+            let similarity = *submatch.get_one::<f32>("similarity").expect("similarity is required");
 
+            let embedder = embedder(submatch)?;
             let container;
-            let v = match submatch.get_one::<bool>("prevector") {
-                Some(true) => {
-                    use rust_bert::pipelines::sentence_embeddings::builder::SentenceEmbeddingsBuilder;
-                    use rust_bert::pipelines::sentence_embeddings::SentenceEmbeddingsModelType;
-                    let model = SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllMiniLmL6V2).create_model().unwrap();
-                    container = model.encode(&[input]).unwrap();
-                    Some(&container[0])
-                },
-                Some(false) => None,
-                None => panic!("Expected prevector flag")
+            let v = if submatch.get_flag("prevector") {
+                container = embedder.encode(&[input])?;
+                Some(&container[0])
+            } else {
+                None
             };
 
-            if p.is_phatic(input, &v).expect("to check is phatic") {
+            let p = phatic_detector(submatch, similarity, embedder).build()?;
+
+            let verdict = p.analyse(input, &v)?;
+            if verdict.phatic {
                 println!("String is phatic");
             } else {
                 println!("String is NOT phatic");
             }
+            if submatch.get_flag("explain") {
+                println!("{}", serde_json::to_string_pretty(&verdict).expect("verdicts serialize"));
+            }
+            Ok(())
         }
 
-        Some(("cluster-ndarray", submatch)) => {
-            let input = get_arg!(submatch, "VECTOR_FILE");
-            let output = get_arg!(submatch, "CLUSTER_FILE");
+        Some(("phatic-batch", submatch)) => {
+            let input = get_arg!(submatch, "TEXT_FILE");
+            let output = get_arg!(submatch, "OUTPUT_FILE");
+
+            let similarity = *submatch.get_one::<f32>("similarity").expect("similarity is required");
 
-            let embeddings = file::load_vectors_from_json(input);
-            let embeddings = cluster::normalize_all_inplace(embeddings);
+            let documents = text_input(submatch).load(input)?;
+            let detector = phatic_detector(submatch, similarity, embedder(submatch)?).build()?;
             time_it!(
-                "main_cluster",
-                let clusters = cluster::cluster_using_ndarray(embeddings);
+                "phatic classification",
+                let verdicts = detector.classify(&documents.texts, &encode_options(submatch))?;
             );
-            file::dump_as_json(output, &clusters);
+            println!("{} of {} documents are phatic", verdicts.iter().filter(|v| v.phatic).count(), verdicts.len());
+
+            let verdicts: Vec<DocumentVerdict> = verdicts
+                .into_iter()
+                .enumerate()
+                .map(|(index, verdict)| DocumentVerdict { index, id: documents.id(index), verdict })
+                .collect();
+            file::dump_as_json(output, &verdicts)
         }
 
-        Some(("cluster-ndarray2", submatch)) => {
-            let input = get_arg!(submatch, "VECTOR_FILE");
-            let output = get_arg!(submatch, "CLUSTER_FILE");
+        Some(("cluster", submatch)) => cluster_file(submatch, algorithm(submatch)),
 
-            let embeddings = file::load_vectors_from_json(input);
-            let embeddings = cluster::normalize_all_inplace(embeddings);
-            time_it!(
-                "main_cluster",
-                let clusters = cluster::cluster_using_ndarray_low_memory(embeddings);
-            );
-            file::dump_as_json(output, &clusters);
-        }
+        Some(("pipeline", submatch)) => pipeline(submatch),
 
-        Some(("cluster-ndarray3", submatch)) => {
+        Some(("cluster-update", submatch)) => {
             let input = get_arg!(submatch, "VECTOR_FILE");
-            let output = get_arg!(submatch, "CLUSTER_FILE");
+            let clusters = get_arg!(submatch, "CLUSTER_FILE");
+            let new_input = get_arg!(submatch, "NEW_VECTOR_FILE");
+            let output = get_arg!(submatch, "OUTPUT_FILE");
 
-            let embeddings = file::load_vectors_from_json(input);
-            let embeddings = cluster::normalize_all_inplace(embeddings);
-            time_it!(
-                "main_cluster",
-                let clusters = cluster::cluster_using_ndarray_batched(embeddings);
-            );
-            file::dump_as_json(output, &clusters);
-        }
+            let params = cluster_params(submatch);
 
-        Some(("cluster-ndarray4", submatch)) => {
-            let input = get_arg!(submatch, "VECTOR_FILE");
-            let output = get_arg!(submatch, "CLUSTER_FILE");
+            let embeddings = load_embeddings(input)?;
+            let new = load_embeddings(new_input)?;
+            let clusters = file::load_clusters_from_json(clusters)?;
+            check_memory_budget(&params, new.view())?;
 
-            let embeddings = file::load_vectors_from_json(input);
-            let embeddings = cluster::normalize_all_inplace(embeddings);
             time_it!(
-                "main_cluster",
-                let clusters = cluster::cluster_using_ndarray_batched_unique_on_the_go(embeddings);
+                "update",
+                let clusters = cluster::update_clusters(clusters, embeddings.view(), new.view(), &params)?;
             );
-            file::dump_as_json(output, &clusters);
+            if rich_output(submatch) {
+                let all = ndarray::concatenate(Axis(0), &[embeddings.view(), new.view()]).expect("update checked the dimensions match");
+                dump_report(submatch, output, &clusters, all.view())
+            } else {
+                file::dump_as_json(output, &clusters)
+            }
         }
 
+        Some(("cache", submatch)) => match submatch.subcommand() {
+            Some(("stats", submatch)) => {
+                let dir = get_arg!(submatch, "cache-dir");
+                for stats in cache::stats(dir.as_ref())? {
+                    print_cache_stats(&stats);
+                }
+                Ok(())
+            }
+            Some(("prune", submatch)) => {
+                let dir = get_arg!(submatch, "cache-dir");
+                let options = PruneOptions {
+                    model: submatch.get_one::<String>("model").cloned(),
+                    unused_for: submatch.get_one::<u64>("older-than").map(|days| days.saturating_mul(24 * 60 * 60)),
+                    max_entries: submatch.get_one::<usize>("max-entries").copied(),
+                };
+                for (before, after) in cache::prune(dir.as_ref(), &options)? {
+                    println!("{}: {} entries, {} bytes, was {} entries, {} bytes", after.model, after.entries, after.bytes, before.entries, before.bytes);
+                }
+                Ok(())
+            }
+            _ => unreachable!(),
+        },
+
+        Some(("cluster-ndarray", submatch)) => cluster_file(submatch, Algorithm::Full),
+        Some(("cluster-ndarray2", submatch)) => cluster_file(submatch, Algorithm::RowWise),
+        Some(("cluster-ndarray3", submatch)) => cluster_file(submatch, Algorithm::Batched),
+        Some(("cluster-ndarray4", submatch)) => cluster_file(submatch, Algorithm::BatchedPrune),
+
         Some(("tsne", submatch)) => {
             let input = get_arg!(submatch, "VECTOR_FILE");
             let output = get_arg!(submatch, "TSNE_FILE");
 
-            let embeddings = file::load_vectors_from_json(input);
-            let embeddings = cluster::normalize_all_inplace(embeddings);
+            let params = cluster_params(submatch);
 
-            let embeddings_copy = embeddings.clone();
+            let vectors = load_embeddings(input)?;
+            let embeddings = vectors.view();
+            check_memory_budget(&params, embeddings)?;
 
             time_it!(
                 "cluster",
-                let clusters = cluster::cluster_using_ndarray_batched(embeddings_copy);
+                let clusters = cluster::Batched.cluster(embeddings, &params);
             );
 
-            let embeddings = cluster::vectors_to_array(embeddings);
             time_it!(
                 "tsne",
-                let reduced = tsne::reduce_dimensions(&clusters, &embeddings);
+                let reduced = tsne::reduce_dimensions(&clusters, embeddings)?;
             );
-            file::dump_as_json(output, &reduced);
+            match vector_format(submatch) {
+                VectorFormat::Json => file::dump_as_json(output, &reduced),
+                format => {
+                    let reduced = Array2::from_shape_vec((reduced.len(), 2), reduced.iter().flat_map(|(x, y)| [*x, *y]).collect())
+                        .expect("two coordinates per point");
+                    // binary isn't one of the tsne formats, so there's no model to tag it with
+                    file::dump_vectors(output, "tsne", "", reduced.view(), format)
+                }
+            }
         }
 
         _ => unreachable!(),
@@ -0,0 +1,65 @@
+use std::fs;
+
+/// Bytes of memory available for new allocations without swapping, read from `/proc/meminfo`.
+/// `None` when it can't be determined, e.g. not running on Linux.
+pub fn available_memory() -> Option<usize> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    parse_mem_available(&meminfo)
+}
+
+/// Parses a size like `512MB`, `1.5GB`, `64K` or `1048576`, units are powers of 1024.
+pub fn parse_size(size: &str) -> Result<usize, String> {
+    let size = size.trim();
+    let split = size.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+
+    let number = number.parse::<f64>().map_err(|_| format!("invalid size {}", size))?;
+    let multiplier: usize = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        "T" | "TB" | "TIB" => 1024 * 1024 * 1024 * 1024,
+        unit => return Err(format!("unknown size unit {} in {}", unit, size)),
+    };
+
+    Ok((number * multiplier as f64) as usize)
+}
+
+fn parse_mem_available(meminfo: &str) -> Option<usize> {
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kilobytes = line
+        .trim_start_matches("MemAvailable:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<usize>()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_it_can_parse_sizes() {
+        assert_eq!(Ok(512 * 1024 * 1024), parse_size("512MB"));
+        assert_eq!(Ok(512 * 1024 * 1024), parse_size("512 mb"));
+        assert_eq!(Ok(1536 * 1024 * 1024), parse_size("1.5G"));
+        assert_eq!(Ok(64 * 1024), parse_size("64KiB"));
+        assert_eq!(Ok(1000), parse_size("1000"));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("12 parsecs").is_err());
+    }
+
+    #[test]
+    fn test_it_can_parse_mem_available() {
+        let meminfo = "MemTotal:       16291212 kB\nMemFree:         9433184 kB\nMemAvailable:   12795308 kB\nBuffers:          201980 kB\n";
+        assert_eq!(Some(12795308 * 1024), parse_mem_available(meminfo));
+        assert_eq!(None, parse_mem_available("MemTotal:       16291212 kB\n"));
+        assert_eq!(None, parse_mem_available(""));
+    }
+}
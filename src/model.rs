@@ -0,0 +1,335 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[cfg(feature = "rust-bert")]
+use rust_bert::pipelines::sentence_embeddings::builder::SentenceEmbeddingsBuilder;
+#[cfg(feature = "rust-bert")]
+use rust_bert::pipelines::sentence_embeddings::{SentenceEmbeddingsModel, SentenceEmbeddingsModelType};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "rust-bert")]
+use crate::cluster::Embedding;
+use crate::embed::{Embedder, HashingEmbedder};
+use crate::error::{Error, Result};
+#[cfg(feature = "rust-bert")]
+use crate::time_it;
+
+/// Environment variable the CLI reads a local model directory from, when there's no `--model-dir`.
+pub const MODEL_DIR_ENV: &str = "CLUSTER_MODEL_DIR";
+
+/// The pretrained sentence embeddings models rust-bert can download, and `HashingEmbedder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ModelType {
+    /// 384 dimensions, what we use in arty
+    #[default]
+    AllMiniLmL6V2,
+    AllMiniLmL12V2,
+    AllDistilrobertaV1,
+    ParaphraseAlbertSmallV2,
+    DistiluseBaseMultilingualCased,
+    BertBaseNliMeanTokens,
+    SentenceT5Base,
+    /// No model files, see `HashingEmbedder`
+    Hashing,
+}
+
+impl ModelType {
+    pub const NAMES: [&'static str; 8] = [
+        "all-MiniLM-L6-v2",
+        "all-MiniLM-L12-v2",
+        "all-distilroberta-v1",
+        "paraphrase-albert-small-v2",
+        "distiluse-base-multilingual-cased",
+        "bert-base-nli-mean-tokens",
+        "sentence-t5-base",
+        "hashing",
+    ];
+
+    const ALL: [ModelType; 8] = [
+        ModelType::AllMiniLmL6V2,
+        ModelType::AllMiniLmL12V2,
+        ModelType::AllDistilrobertaV1,
+        ModelType::ParaphraseAlbertSmallV2,
+        ModelType::DistiluseBaseMultilingualCased,
+        ModelType::BertBaseNliMeanTokens,
+        ModelType::SentenceT5Base,
+        ModelType::Hashing,
+    ];
+
+    /// Name on the Hugging Face hub, under `sentence-transformers/`, or `hashing`.
+    pub fn name(self) -> &'static str {
+        let position = ModelType::ALL.iter().position(|t| *t == self).expect("every type is in ALL");
+        ModelType::NAMES[position]
+    }
+
+    #[cfg(feature = "rust-bert")]
+    fn rust_bert(self) -> SentenceEmbeddingsModelType {
+        match self {
+            ModelType::AllMiniLmL6V2 => SentenceEmbeddingsModelType::AllMiniLmL6V2,
+            ModelType::AllMiniLmL12V2 => SentenceEmbeddingsModelType::AllMiniLmL12V2,
+            ModelType::AllDistilrobertaV1 => SentenceEmbeddingsModelType::AllDistilrobertaV1,
+            ModelType::ParaphraseAlbertSmallV2 => SentenceEmbeddingsModelType::ParaphraseAlbertSmallV2,
+            ModelType::DistiluseBaseMultilingualCased => SentenceEmbeddingsModelType::DistiluseBaseMultilingualCased,
+            ModelType::BertBaseNliMeanTokens => SentenceEmbeddingsModelType::BertBaseNliMeanTokens,
+            ModelType::SentenceT5Base => SentenceEmbeddingsModelType::SentenceT5Base,
+            ModelType::Hashing => unreachable!("hashing isn't a rust-bert model"),
+        }
+    }
+}
+
+impl fmt::Display for ModelType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ModelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ModelType, String> {
+        ModelType::ALL
+            .into_iter()
+            .find(|t| t.name() == s)
+            .ok_or_else(|| format!("unknown model {}, expected one of {}", s, ModelType::NAMES.join(", ")))
+    }
+}
+
+/// Which sentence embeddings model to load, downloaded from the hub by type, or from a local
+/// directory laid out like rust-bert's converted models, for hosts without internet access.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ModelSource {
+    model_type: ModelType,
+    model_dir: Option<PathBuf>,
+}
+
+impl ModelSource {
+    pub fn new() -> ModelSource {
+        ModelSource::default()
+    }
+
+    /// Model to download, ignored when loading from a directory.
+    pub fn with_model_type(mut self, model_type: ModelType) -> ModelSource {
+        self.model_type = model_type;
+        self
+    }
+
+    /// Loads the model from `model_dir` instead of downloading it.
+    pub fn with_model_dir<P: Into<PathBuf>>(mut self, model_dir: P) -> ModelSource {
+        self.model_dir = Some(model_dir.into());
+        self
+    }
+
+    /// The model type, or the name of the directory followed by a fingerprint of where it is and
+    /// its config and weights, so caches of different models in same named directories don't mix.
+    fn name(&self) -> String {
+        match &self.model_dir {
+            Some(dir) => {
+                let dir = dir.canonicalize().unwrap_or_else(|_| dir.clone());
+                let name = dir
+                    .file_name()
+                    .map_or_else(|| dir.display().to_string(), |name| name.to_string_lossy().into_owned());
+                format!("{}-{}", name, model_dir_fingerprint(&dir))
+            }
+            None => self.model_type.name().to_string(),
+        }
+    }
+
+    /// Loads the model. A local directory is checked first, rust-bert panics on missing files.
+    /// Models can be moved to another thread, see `ParallelEmbedder`.
+    pub fn load(&self) -> Result<Box<dyn Embedder + Send>> {
+        match (&self.model_dir, self.model_type) {
+            (None, ModelType::Hashing) => Ok(Box::new(HashingEmbedder::new())),
+            _ => self.load_rust_bert(),
+        }
+    }
+
+    #[cfg(feature = "rust-bert")]
+    fn load_rust_bert(&self) -> Result<Box<dyn Embedder + Send>> {
+        time_it!(
+            "loading sentence_embeddings model",
+            let model = match &self.model_dir {
+                Some(dir) => {
+                    check_model_dir(dir)?;
+                    SentenceEmbeddingsBuilder::local(dir).create_model()?
+                }
+                None => SentenceEmbeddingsBuilder::remote(self.model_type.rust_bert()).create_model()?,
+            };
+        );
+        // rust-bert doesn't say, so embed something to find out
+        let dimension = model.encode(&["dimension"])?[0].len();
+        // and the tokens added to every text, tokenizing nothing is only those
+        let special_tokens = model.tokenize(&[""]).tokens_ids[0].numel();
+        Ok(Box::new(BertEmbedder { model, name: self.name(), dimension, special_tokens }))
+    }
+
+    #[cfg(not(feature = "rust-bert"))]
+    fn load_rust_bert(&self) -> Result<Box<dyn Embedder + Send>> {
+        Err(Error::Model(format!(
+            "can't load {}, built without the rust-bert feature, only the hashing model is available",
+            self.name()
+        )))
+    }
+}
+
+/// A rust-bert sentence embeddings model.
+#[cfg(feature = "rust-bert")]
+struct BertEmbedder {
+    model: SentenceEmbeddingsModel,
+    name: String,
+    dimension: usize,
+    special_tokens: usize,
+}
+
+#[cfg(feature = "rust-bert")]
+impl Embedder for BertEmbedder {
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Embedding>> {
+        Ok(self.model.encode(texts)?)
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Texts are truncated to the model's maximum sequence length, so longer ones count as that.
+    fn count_tokens(&self, text: &str) -> Result<Option<usize>> {
+        let tokens = self.model.tokenize(&[text]).tokens_ids[0].numel();
+        Ok(Some(tokens.saturating_sub(self.special_tokens)))
+    }
+}
+
+/// First 8 hex digits of a sha256 of the path of a model directory, and the size and modified
+/// time of its config and weights, which change when another model is converted into it.
+fn model_dir_fingerprint(dir: &Path) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(dir.to_string_lossy().as_bytes());
+    for file in ["config.json", "rust_model.ot"] {
+        let metadata = std::fs::metadata(dir.join(file));
+        let modified = metadata
+            .as_ref()
+            .ok()
+            .and_then(|m| m.modified().ok())
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok());
+        hasher.update(format!("\0{}:{:?}:{:?}", file, metadata.map(|m| m.len()).ok(), modified).as_bytes());
+    }
+    hasher.finalize()[..4].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Checks a local model directory has everything `SentenceEmbeddingsBuilder::local` reads,
+/// naming every missing file.
+#[cfg_attr(not(feature = "rust-bert"), allow(dead_code))]
+fn check_model_dir(dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        return Err(Error::Model(format!("model directory {} doesn't exist", dir.display())));
+    }
+    let missing = missing_model_files(dir)?;
+    if !missing.is_empty() {
+        return Err(Error::Model(format!(
+            "model directory {} is missing {} (rust_model.ot is converted from pytorch_model.bin with rust-bert's utils/convert_model.py)",
+            dir.display(),
+            missing.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Files missing from a local model directory, relative to it. Which ones are needed depends on
+/// `modules.json` and the `model_type` in `config.json`, so those are read when they're there.
+#[cfg_attr(not(feature = "rust-bert"), allow(dead_code))]
+fn missing_model_files(dir: &Path) -> Result<Vec<String>> {
+    let read_json = |name: &str| -> Result<Option<Value>> {
+        match std::fs::read(dir.join(name)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| Error::Model(format!("{}: {}", dir.join(name).display(), e))),
+            Err(_) => Ok(None),
+        }
+    };
+
+    let mut needed: Vec<String> = ["modules.json", "config.json", "rust_model.ot", "tokenizer_config.json", "sentence_bert_config.json"]
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+
+    if let Some(Value::Array(modules)) = read_json("modules.json")? {
+        for module in modules {
+            let path = module.get("path").and_then(Value::as_str).unwrap_or("");
+            match module.get("type").and_then(Value::as_str).unwrap_or("") {
+                t if t.ends_with("Pooling") => needed.push(format!("{}/config.json", path)),
+                t if t.ends_with("Dense") => {
+                    needed.push(format!("{}/config.json", path));
+                    needed.push(format!("{}/rust_model.ot", path));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(config) = read_json("config.json")? {
+        match config.get("model_type").and_then(Value::as_str) {
+            Some("bert") | Some("distilbert") => needed.push("vocab.txt".to_string()),
+            Some("roberta") => needed.extend(["vocab.json".to_string(), "merges.txt".to_string()]),
+            Some("albert") | Some("t5") => needed.push("spiece.model".to_string()),
+            _ => {}
+        }
+    }
+
+    Ok(needed.into_iter().filter(|name| !dir.join(name).is_file()).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_type_names_round_trip() {
+        for name in ModelType::NAMES {
+            assert_eq!(name, name.parse::<ModelType>().unwrap().name());
+        }
+        assert!("all-MiniLM-L6".parse::<ModelType>().is_err());
+        assert_eq!("all-MiniLM-L6-v2", ModelSource::new().name());
+    }
+
+    #[test]
+    fn test_it_lists_missing_model_files() {
+        let dir = std::env::temp_dir().join(format!("cluster-model-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("1_Pooling")).unwrap();
+        std::fs::write(dir.join("modules.json"), r#"[
+            {"idx": 0, "name": "0", "path": "", "type": "sentence_transformers.models.Transformer"},
+            {"idx": 1, "name": "1", "path": "1_Pooling", "type": "sentence_transformers.models.Pooling"}
+        ]"#).unwrap();
+        std::fs::write(dir.join("config.json"), r#"{"model_type": "bert"}"#).unwrap();
+        std::fs::write(dir.join("1_Pooling/config.json"), "{}").unwrap();
+
+        assert_eq!(
+            vec!["rust_model.ot", "tokenizer_config.json", "sentence_bert_config.json", "vocab.txt"],
+            missing_model_files(&dir).unwrap()
+        );
+        let error = check_model_dir(&dir).unwrap_err().to_string();
+        assert!(error.contains("is missing rust_model.ot, tokenizer_config.json"), "{}", error);
+
+        std::fs::write(dir.join("config.json"), "{").unwrap();
+        assert!(matches!(missing_model_files(&dir), Err(Error::Model(_))));
+        assert!(check_model_dir(&dir.join("nope")).is_err());
+
+        // same named directories, or the same one with another model converted into it, differ
+        let name = ModelSource::new().with_model_dir(&dir).name();
+        let base = dir.file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with(&format!("{}-", base)) && name.len() == base.len() + 9, "{}", name);
+        assert_eq!(name, ModelSource::new().with_model_dir(&dir).name());
+        std::fs::create_dir_all(dir.join("other").join(&base)).unwrap();
+        let other = ModelSource::new().with_model_dir(dir.join("other").join(&base)).name();
+        assert!(other.starts_with(&format!("{}-", base)));
+        assert_ne!(name, other);
+        std::fs::write(dir.join("config.json"), r#"{"model_type": "roberta"}"#).unwrap();
+        assert_ne!(name, ModelSource::new().with_model_dir(&dir).name());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
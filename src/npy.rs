@@ -0,0 +1,248 @@
+use std::io::{self, Read, Seek, Write};
+
+use half::f16;
+use ndarray::prelude::*;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// First bytes of every `.npy` file.
+pub const MAGIC: &[u8; 6] = b"\x93NUMPY";
+/// First bytes of every `.npz` file, which is a zip archive of `.npy` files.
+pub const ZIP_MAGIC: &[u8; 4] = b"PK\x03\x04";
+/// Magic, version and header length come before the header, which is padded so the data
+/// starts on a multiple of this.
+const HEADER_ALIGN: usize = 64;
+
+/// Element types that can be read and written, little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NpyDtype {
+    F32,
+    F16,
+}
+
+impl NpyDtype {
+    fn descr(self) -> &'static str {
+        match self {
+            NpyDtype::F32 => "<f4",
+            NpyDtype::F16 => "<f2",
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            NpyDtype::F32 => 4,
+            NpyDtype::F16 => 2,
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The text after `'key':` in a npy header, which is a python dict literal.
+fn dict_value<'h>(header: &'h str, key: &str) -> io::Result<&'h str> {
+    let key = format!("'{}':", key);
+    header
+        .find(&key)
+        .map(|at| header[at + key.len()..].trim_start())
+        .ok_or_else(|| invalid(format!("npy header has no {}", key)))
+}
+
+/// Parses the dtype, fortran order and shape out of a npy header.
+fn parse_header(header: &str) -> io::Result<(NpyDtype, (usize, usize))> {
+    let descr = dict_value(header, "descr")?;
+    let dtype = match descr.get(..5) {
+        Some("'<f4'") => NpyDtype::F32,
+        Some("'<f2'") => NpyDtype::F16,
+        _ => return Err(invalid(format!("unsupported npy dtype {}, expected '<f4' or '<f2'", descr.split(',').next().unwrap_or("")))),
+    };
+
+    if dict_value(header, "fortran_order")?.starts_with("True") {
+        return Err(invalid("fortran order npy arrays aren't supported, save with C order".to_string()));
+    }
+
+    let shape = dict_value(header, "shape")?;
+    let shape = shape
+        .strip_prefix('(')
+        .and_then(|shape| shape.split(')').next())
+        .ok_or_else(|| invalid(format!("can't parse npy shape {}", shape)))?;
+    let shape = shape
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<usize>().map_err(|_| invalid(format!("can't parse npy shape ({})", shape))))
+        .collect::<io::Result<Vec<usize>>>()?;
+    match shape[..] {
+        [rows, columns] => Ok((dtype, (rows, columns))),
+        _ => Err(invalid(format!("expected a 2-D npy array, found shape ({})", shape.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(", ")))),
+    }
+}
+
+/// Reads a 2-D float32 or float16 C order `.npy` array, converting to f32.
+pub fn read_npy<R: Read>(reader: &mut R) -> io::Result<Array2<f32>> {
+    let mut preamble = [0u8; 8];
+    reader.read_exact(&mut preamble)?;
+    if preamble[..6] != MAGIC[..] {
+        return Err(invalid("not a npy file".to_string()));
+    }
+
+    let header_len = match preamble[6] {
+        1 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_le_bytes(len) as usize
+        }
+        2 | 3 => {
+            let mut len = [0u8; 4];
+            reader.read_exact(&mut len)?;
+            u32::from_le_bytes(len) as usize
+        }
+        version => return Err(invalid(format!("unsupported npy version {}", version))),
+    };
+    let mut header = vec![0u8; header_len];
+    reader.read_exact(&mut header)?;
+    let header = String::from_utf8(header).map_err(|_| invalid("npy header isn't utf-8".to_string()))?;
+    let (dtype, shape) = parse_header(&header)?;
+
+    let len = shape
+        .0
+        .checked_mul(shape.1)
+        .and_then(|values| values.checked_mul(dtype.size()))
+        .ok_or_else(|| invalid(format!("npy shape ({}, {}) doesn't fit in memory", shape.0, shape.1)))?;
+    // read rather than allocate up front, so a bad shape fails at the end of the data
+    let mut data = Vec::new();
+    reader.take(len as u64).read_to_end(&mut data)?;
+    if data.len() != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("expected {} bytes of npy data, found {}", len, data.len()),
+        ));
+    }
+    let values: Vec<f32> = match dtype {
+        NpyDtype::F32 => data.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect(),
+        NpyDtype::F16 => data.chunks_exact(2).map(|b| f16::from_le_bytes(b.try_into().unwrap()).to_f32()).collect(),
+    };
+
+    Ok(Array2::from_shape_vec(shape, values).expect("read exactly rows * columns values"))
+}
+
+/// Writes a 2-D array as a version 1.0 C order `.npy` file, converting to `dtype`.
+pub fn write_npy<W: Write>(writer: &mut W, array: ArrayView2<f32>, dtype: NpyDtype) -> io::Result<()> {
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': ({}, {}), }}",
+        dtype.descr(),
+        array.nrows(),
+        array.ncols()
+    );
+    // magic, 2 version bytes and 2 length bytes, then the header ending in a newline
+    let unpadded = MAGIC.len() + 4 + header.len() + 1;
+    header.push_str(&" ".repeat(unpadded.next_multiple_of(HEADER_ALIGN) - unpadded));
+    header.push('\n');
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[1, 0])?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for value in array.iter() {
+        match dtype {
+            NpyDtype::F32 => writer.write_all(&value.to_le_bytes())?,
+            NpyDtype::F16 => writer.write_all(&f16::from_f32(*value).to_le_bytes())?,
+        }
+    }
+    Ok(())
+}
+
+/// Reads the array called `name` from a `.npz` archive, or the only array if there's just one.
+pub fn read_npz<R: Read + Seek>(reader: R, name: &str) -> io::Result<Array2<f32>> {
+    let mut archive = ZipArchive::new(reader)?;
+    let filename = format!("{}.npy", name);
+
+    if archive.file_names().any(|f| f == filename) {
+        read_npy(&mut archive.by_name(&filename)?)
+    } else if archive.len() == 1 {
+        read_npy(&mut archive.by_index(0)?)
+    } else {
+        let mut names: Vec<&str> = archive.file_names().map(|f| f.trim_end_matches(".npy")).collect();
+        names.sort_unstable();
+        Err(invalid(format!("npz has no array called {}, found {}", name, names.join(", "))))
+    }
+}
+
+/// Writes arrays to a `.npz` archive, uncompressed like `numpy.savez`.
+pub fn write_npz<W: Write + Seek>(writer: W, arrays: &[(&str, ArrayView2<f32>)], dtype: NpyDtype) -> io::Result<()> {
+    let mut archive = ZipWriter::new(writer);
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    for (name, array) in arrays {
+        archive.start_file(format!("{}.npy", name), options)?;
+        write_npy(&mut archive, *array, dtype)?;
+    }
+    archive.finish()?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_it_can_parse_headers() {
+        assert_eq!(
+            (NpyDtype::F32, (3, 384)),
+            parse_header("{'descr': '<f4', 'fortran_order': False, 'shape': (3, 384), }").unwrap()
+        );
+        assert_eq!(
+            (NpyDtype::F16, (0, 2)),
+            parse_header("{'descr': '<f2', 'fortran_order': False, 'shape': (0, 2), }      \n").unwrap()
+        );
+        assert!(parse_header("{'descr': '<f8', 'fortran_order': False, 'shape': (3, 4), }").is_err());
+        assert!(parse_header("{'descr': '<f4', 'fortran_order': True, 'shape': (3, 4), }").is_err());
+        assert!(parse_header("{'descr': '<f4', 'fortran_order': False, 'shape': (3,), }").is_err());
+    }
+
+    #[test]
+    fn test_it_can_read_what_numpy_writes() {
+        // numpy.save(f, numpy.array([[1, 2], [3, 4]], dtype=numpy.float16))
+        let mut bytes = b"\x93NUMPY\x01\x00\x76\x00".to_vec();
+        bytes.extend_from_slice(b"{'descr': '<f2', 'fortran_order': False, 'shape': (2, 2), }");
+        bytes.extend_from_slice(&[b' '; 58]);
+        bytes.push(b'\n');
+        bytes.extend_from_slice(&[0x00, 0x3c, 0x00, 0x40, 0x00, 0x42, 0x00, 0x44]);
+
+        assert_eq!(array![[1.0, 2.0], [3.0, 4.0]], read_npy(&mut Cursor::new(bytes.clone())).unwrap());
+
+        bytes.pop();
+        assert_eq!(io::ErrorKind::UnexpectedEof, read_npy(&mut Cursor::new(bytes)).unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_npy_round_trips() {
+        let array = array![[0.5, -1.0, 2.0], [3.25, 0.0, -0.125]];
+        for dtype in [NpyDtype::F32, NpyDtype::F16] {
+            let mut bytes = vec![];
+            write_npy(&mut bytes, array.view(), dtype).unwrap();
+            assert_eq!(0, (bytes.len() - array.len() * dtype.size()) % HEADER_ALIGN);
+            assert_eq!(array, read_npy(&mut Cursor::new(bytes)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_npz_round_trips() {
+        let embeddings = array![[1.0, 0.0], [0.0, 1.0]];
+        let tsne = array![[10.0, -10.0]];
+
+        let mut bytes = Cursor::new(vec![]);
+        write_npz(&mut bytes, &[("embeddings", embeddings.view()), ("tsne", tsne.view())], NpyDtype::F32).unwrap();
+        assert_eq!(ZIP_MAGIC[..], bytes.get_ref()[..4]);
+
+        assert_eq!(tsne, read_npz(Cursor::new(bytes.get_ref()), "tsne").unwrap());
+        assert_eq!(embeddings, read_npz(Cursor::new(bytes.get_ref()), "embeddings").unwrap());
+        assert!(read_npz(Cursor::new(bytes.get_ref()), "other").is_err());
+
+        let mut single = Cursor::new(vec![]);
+        write_npz(&mut single, &[("arr_0", tsne.view())], NpyDtype::F32).unwrap();
+        assert_eq!(tsne, read_npz(single, "embeddings").unwrap());
+    }
+}
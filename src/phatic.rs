@@ -1,163 +1,699 @@
-use lazy_static::lazy_static;
-use regex::Regex;
-use std::error::Error;
 use std::mem::take;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use ndarray::prelude::*;
 
-use rust_bert::pipelines::sentence_embeddings::{
-    Embedding,
-    SentenceEmbeddingsModel,
-    SentenceEmbeddingsModelType,
-    builder::SentenceEmbeddingsBuilder,
-};
-use crate::cluster;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 
+use crate::binary::{self, VectorFile};
+use crate::cluster::{self, Embedding};
+use crate::embed::Embedder;
+use crate::error::{Error, Result};
+use crate::file::EncodeOptions;
+use crate::model::ModelSource;
+use crate::preprocess::sanitise_text;
+use crate::timer::Progress;
+
+/// Detects phatic text (greetings, thanks, small talk) by comparing sentence embeddings against
+/// a set of example phatic sentences, and optionally a set of examples that aren't phatic.
+/// Construct with `PhaticDetectorBuilder`.
 pub struct PhaticDetector {
-    model: SentenceEmbeddingsModel,
+    embedder: Box<dyn Embedder>,
+    examples: Vec<String>,
+    /// Normalized example embeddings, one per column
     embeddings: Array<f32, Ix2>,
+    /// Normalized negative example embeddings, one per column, when there are any
+    negatives: Option<Array<f32, Ix2>>,
     similarity: f32,
+    words: WordLimits,
 }
 
 static EXAMPLES: &str = include_str!("phatic_examples.txt");
 
 impl PhaticDetector {
-    fn new(similarity: f32) -> Result<PhaticDetector, Box<dyn Error>> {
-        let model = SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllMiniLmL6V2).create_model()?;
+    /// `embeddings` are normalized example embeddings, one per row, one for each of `examples`
+    /// then one for each negative example.
+    fn new(similarity: f32, words: WordLimits, embedder: Box<dyn Embedder>, examples: Vec<String>, embeddings: Array<f32, Ix2>) -> PhaticDetector {
+        let positives = examples.len();
+        let negatives = (embeddings.nrows() > positives).then(|| embeddings.slice(s![positives.., ..]).reversed_axes().to_owned());
+        let embeddings = embeddings.slice(s![..positives, ..]).reversed_axes().to_owned();
 
-        let embeddings = EXAMPLES.lines().map(|s| model.encode(&[s]).unwrap()).flatten().collect(); // @todo panic!
-        let embeddings = cluster::normalize_all_inplace(embeddings);
-        let embeddings = cluster::vectors_to_array(embeddings);
-        let embeddings = embeddings.reversed_axes();
+        PhaticDetector { embedder, examples, embeddings, negatives, similarity, words }
+    }
 
-        Ok(PhaticDetector { model, embeddings, similarity })
+    /// Text with fewer than 4 words is always phatic, with more than 14 never is, anything in
+    /// between is phatic when it is similar enough to one of the examples, and closer to it than
+    /// to any negative example. `PhaticDetectorBuilder::with_min_words` and `with_max_words`
+    /// change the limits. Pass a precomputed (normalized) `embedding` of `text` to skip encoding
+    /// it again.
+    pub fn is_phatic(&self, text: &str, embedding: &Option<&Embedding>) -> Result<bool> {
+        Ok(self.analyse(text, embedding)?.phatic)
     }
 
-    pub fn is_phatic(&self, text: &str, embedding: &Option<&Embedding>) -> Result<bool, Box<dyn Error>> {
+    /// Decides like `is_phatic`, and says why.
+    pub fn analyse(&self, text: &str, embedding: &Option<&Embedding>) -> Result<PhaticVerdict> {
         let text = sanitise_text(text);
-        match text.split(char::is_whitespace).count() {
-            0..=3 => Ok(true),
-            15.. => Ok(false),
-            _ => vector_check(&text, embedding, self)
+        match self.word_count_rule(&text)? {
+            Some(rule) => Ok(PhaticVerdict::from_word_count(rule, text)),
+            None => vector_check(text, embedding, self)
+        }
+    }
+
+    /// Analyses many texts like `analyse`, encoding the ones the word count doesn't decide a
+    /// batch at a time, and comparing each batch to the examples in one matrix multiply.
+    pub fn classify(&self, texts: &[String], options: &EncodeOptions) -> Result<Vec<PhaticVerdict>> {
+        let mut verdicts = Vec::with_capacity(texts.len());
+        let mut remaining = self.word_count_verdicts(texts, &mut verdicts)?;
+
+        let mut progress = options.progress().then(|| Progress::start("phatic", remaining.len()));
+        for batch in remaining.chunks_mut(options.batch_size()) {
+            let texts: Vec<String> = batch.iter_mut().map(|(_, text)| take(text)).collect();
+            let embeddings = self.embedder.encode(&texts.iter().map(String::as_str).collect::<Vec<&str>>())?;
+            let embeddings = cluster::vectors_to_array(cluster::normalize_all_inplace(embeddings))?;
+            for ((i, _), verdict) in batch.iter().zip(self.compare(embeddings.view(), texts)?) {
+                verdicts[*i] = Some(verdict);
+            }
+            if let Some(progress) = &mut progress {
+                progress.add(batch.len());
+            }
+        }
+        if let Some(progress) = progress {
+            progress.finish();
+        }
+
+        Ok(verdicts.into_iter().map(|verdict| verdict.expect("every text was classified")).collect())
+    }
+
+    /// Same as `classify`, with precomputed (normalized) `embeddings` of the texts, one row each,
+    /// compared to the examples in one matrix multiply.
+    pub fn classify_embedded(&self, texts: &[String], embeddings: ArrayView2<f32>) -> Result<Vec<PhaticVerdict>> {
+        if embeddings.nrows() != texts.len() {
+            return Err(Error::invalid_parameter(
+                "embeddings",
+                format!("{} embeddings for {} texts", embeddings.nrows(), texts.len()),
+            ));
+        }
+        let mut verdicts = Vec::with_capacity(texts.len());
+        let remaining = self.word_count_verdicts(texts, &mut verdicts)?;
+
+        let (rows, texts): (Vec<usize>, Vec<String>) = remaining.into_iter().unzip();
+        let compared = self.compare(embeddings.select(Axis(0), &rows).view(), texts)?;
+        for (i, verdict) in rows.into_iter().zip(compared) {
+            verdicts[i] = Some(verdict);
+        }
+
+        Ok(verdicts.into_iter().map(|verdict| verdict.expect("every text was classified")).collect())
+    }
+
+    /// Pushes the verdicts the word count decides to `verdicts`, `None` for the rest, returning
+    /// the index and sanitised text of each text left to compare to the examples.
+    fn word_count_verdicts(&self, texts: &[String], verdicts: &mut Vec<Option<PhaticVerdict>>) -> Result<Vec<(usize, String)>> {
+        let mut remaining = vec![];
+        for (i, text) in texts.iter().enumerate() {
+            let text = sanitise_text(text);
+            match self.word_count_rule(&text)? {
+                Some(rule) => verdicts.push(Some(PhaticVerdict::from_word_count(rule, text))),
+                None => {
+                    verdicts.push(None);
+                    remaining.push((i, text));
+                }
+            }
+        }
+        Ok(remaining)
+    }
+
+    /// A verdict for each row of normalized `embeddings` of sanitised `texts`, from their
+    /// similarities to the examples.
+    fn compare(&self, embeddings: ArrayView2<f32>, texts: Vec<String>) -> Result<Vec<PhaticVerdict>> {
+        if embeddings.ncols() != self.embeddings.nrows() {
+            return Err(Error::DimensionMismatch {
+                input: "embedding".to_string(),
+                row: 0,
+                expected: self.embeddings.nrows(),
+                found: embeddings.ncols(),
+            });
+        }
+        let scores = embeddings.dot(&self.embeddings);
+        let negatives = self.negatives.as_ref().map(|negatives| embeddings.dot(negatives));
+
+        Ok(scores
+            .outer_iter()
+            .zip(texts)
+            .enumerate()
+            .map(|(row, (scores, text))| {
+                let (nearest, similarity) = scores
+                    .iter()
+                    .enumerate()
+                    .fold((0, f32::NEG_INFINITY), |best, (i, v)| if *v > best.1 { (i, *v) } else { best });
+                let negative_similarity = negatives
+                    .as_ref()
+                    .map(|negatives| negatives.row(row).fold(f32::NEG_INFINITY, |max, v| max.max(*v)));
+                let rule = if similarity <= self.similarity {
+                    PhaticRule::NotSimilar
+                } else if negative_similarity.is_some_and(|negative| negative >= similarity) {
+                    PhaticRule::NegativeExample
+                } else {
+                    PhaticRule::Similar
+                };
+                PhaticVerdict {
+                    phatic: rule == PhaticRule::Similar,
+                    rule,
+                    similarity: Some(similarity),
+                    nearest_example: Some(nearest),
+                    nearest_example_text: Some(self.examples[nearest].clone()),
+                    negative_similarity,
+                    text,
+                }
+            })
+            .collect())
+    }
+}
+
+/// How words are counted for the word limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WordCount {
+    /// Runs of text between whitespace, which doesn't work for languages written without spaces
+    #[default]
+    Whitespace,
+    /// Tokens of the model's tokenizer, see `Embedder::count_tokens`. Subword tokenizers split
+    /// rare words into several tokens, so limits are usually set higher than for words.
+    Tokenizer,
+}
+
+impl WordCount {
+    pub const NAMES: [&'static str; 2] = ["whitespace", "tokenizer"];
+}
+
+impl FromStr for WordCount {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<WordCount, String> {
+        match s {
+            "whitespace" => Ok(WordCount::Whitespace),
+            "tokenizer" => Ok(WordCount::Tokenizer),
+            _ => Err(format!("unknown word count {}, expected one of {}", s, WordCount::NAMES.join(", "))),
+        }
+    }
+}
+
+/// Text with fewer than `min` words is phatic, and with more than `max` isn't, without comparing
+/// it to the examples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct WordLimits {
+    min: Option<usize>,
+    max: Option<usize>,
+    count: WordCount,
+}
+
+impl Default for WordLimits {
+    fn default() -> WordLimits {
+        WordLimits { min: Some(4), max: Some(14), count: WordCount::Whitespace }
+    }
+}
+
+impl PhaticDetector {
+    /// The rule that decides by the word count alone, `None` when it's within the limits.
+    fn word_count_rule(&self, text: &str) -> Result<Option<PhaticRule>> {
+        let words = match self.words.count {
+            WordCount::Whitespace => text.split(char::is_whitespace).count(),
+            WordCount::Tokenizer => self.embedder.count_tokens(text)?.expect("checked on build"),
+        };
+        if self.words.min.is_some_and(|min| words < min) {
+            Ok(Some(PhaticRule::FewWords))
+        } else if self.words.max.is_some_and(|max| words > max) {
+            Ok(Some(PhaticRule::ManyWords))
+        } else {
+            Ok(None)
         }
     }
 }
 
+/// Which rule decided whether a text is phatic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PhaticRule {
+    /// So few words it's phatic without comparing it to anything
+    FewWords,
+    /// So many words it isn't phatic, without comparing it to anything
+    ManyWords,
+    /// More similar than the threshold to an example, and closer to it than to any negative one
+    Similar,
+    /// Not similar enough to any example
+    NotSimilar,
+    /// Similar enough to an example, but at least as close to a negative example
+    NegativeExample,
+}
+
+/// What `PhaticDetector::analyse` decided about a text, and why.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PhaticVerdict {
+    pub phatic: bool,
+    pub rule: PhaticRule,
+    /// Highest cosine similarity to an example, when the word count didn't decide
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub similarity: Option<f32>,
+    /// Index of the most similar example
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nearest_example: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nearest_example_text: Option<String>,
+    /// Highest cosine similarity to a negative example, when there are any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub negative_similarity: Option<f32>,
+    /// The text after `sanitise_text`, what was counted and compared
+    pub text: String,
+}
+
+impl PhaticVerdict {
+    fn from_word_count(rule: PhaticRule, text: String) -> PhaticVerdict {
+        PhaticVerdict {
+            phatic: rule == PhaticRule::FewWords,
+            rule,
+            similarity: None,
+            nearest_example: None,
+            nearest_example_text: None,
+            negative_similarity: None,
+            text,
+        }
+    }
+}
+
+/// Normalized embeddings of example sentences, one per row.
+fn encode_examples(embedder: &dyn Embedder, examples: &[&str]) -> Result<Array<f32, Ix2>> {
+    let embeddings = embedder.encode(examples)?;
+    let embeddings = cluster::normalize_all_inplace(embeddings);
+    cluster::vectors_to_array(embeddings)
+}
+
+/// What example embeddings saved to a file were made from, the model's name, fingerprinted for
+/// models loaded from a directory, and a sha256 of the examples and negative examples. Saved
+/// embeddings with any other tag are stale.
+fn examples_tag(model: &str, examples: &[&str], positives: usize) -> String {
+    let mut hasher = Sha256::new();
+    for (i, example) in examples.iter().enumerate() {
+        hasher.update(if i < positives { b"+" } else { b"-" });
+        hasher.update(example.as_bytes());
+        hasher.update(b"\n");
+    }
+    let hash: String = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{} phatic-examples-sha256:{}", model, hash)
+}
+
+/// Reads example embeddings saved by an earlier build, if they're for the same model and
+/// examples, otherwise encodes the examples and saves them for next time.
+fn load_or_encode_examples(embedder: &dyn Embedder, examples: &[&str], positives: usize, path: &Path) -> Result<Array<f32, Ix2>> {
+    let filename = path.display().to_string();
+    let tag = examples_tag(&embedder.name(), examples, positives);
+    if let Ok(saved) = VectorFile::open(&filename) {
+        let header = saved.header();
+        if header.model == tag && header.count == examples.len() && header.dimension == embedder.dimension() {
+            println!("loaded {} phatic example embeddings from {}", header.count, filename);
+            return Ok(saved.view().to_owned());
+        }
+    }
+
+    let embeddings = encode_examples(embedder, examples)?;
+    binary::write(&filename, embeddings.view(), &tag).map_err(|e| Error::io(&filename, e))?;
+    println!("saved {} phatic example embeddings to {}", embeddings.nrows(), filename);
+    Ok(embeddings)
+}
+
+/// Where example sentences come from.
+enum Examples {
+    Texts(Vec<String>),
+    /// One per line, blank lines and lines starting with `#` are skipped
+    File(PathBuf),
+}
+
+impl Examples {
+    fn load(&self) -> Result<Vec<String>> {
+        match self {
+            Examples::Texts(texts) => Ok(texts.clone()),
+            Examples::File(path) => {
+                let filename = path.display().to_string();
+                let text = std::fs::read_to_string(path).map_err(|e| Error::io(&filename, e))?;
+                let examples: Vec<String> = text
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_string)
+                    .collect();
+                if examples.is_empty() {
+                    return Err(Error::EmptyInput(filename));
+                }
+                Ok(examples)
+            }
+        }
+    }
+}
+
+/// Configures and builds a `PhaticDetector`, loading the sentence embeddings model.
 pub struct PhaticDetectorBuilder {
     similarity_threshold: f32,
+    model: ModelSource,
+    embedder: Option<Box<dyn Embedder>>,
+    examples: Examples,
+    negative_examples: Examples,
+    embeddings_file: Option<PathBuf>,
+    words: WordLimits,
+}
+
+impl Default for PhaticDetectorBuilder {
+    fn default() -> PhaticDetectorBuilder {
+        PhaticDetectorBuilder::new()
+    }
 }
 
 impl PhaticDetectorBuilder {
     pub fn new() -> PhaticDetectorBuilder {
-        PhaticDetectorBuilder { similarity_threshold: 0.5 }
+        PhaticDetectorBuilder {
+            similarity_threshold: 0.5,
+            model: ModelSource::default(),
+            embedder: None,
+            examples: Examples::Texts(EXAMPLES.lines().map(str::to_string).collect()),
+            negative_examples: Examples::Texts(vec![]),
+            embeddings_file: None,
+            words: WordLimits::default(),
+        }
     }
 
+    /// Cosine similarity to an example above which text is phatic.
     pub fn with_similarity_threshold(mut self, threshold: f32) -> PhaticDetectorBuilder {
         self.similarity_threshold = threshold;
         self
     }
 
-    pub fn build(self) -> Result<PhaticDetector, Box<dyn Error>> {
-        PhaticDetector::new(self.similarity_threshold)
+    /// Sentence embeddings model to compare text to the examples with.
+    pub fn with_model(mut self, model: ModelSource) -> PhaticDetectorBuilder {
+        self.model = model;
+        self
     }
-}
 
-fn vector_check(text: &str, embedding: &Option<&Embedding>, p: &PhaticDetector) -> Result<bool, Box<dyn Error>>
-{
-    let embedding_array = if embedding.is_some() {
-        let embedding = embedding.unwrap();
-        Array::from_shape_vec((1, embedding.len()), embedding.clone())?
+    /// An embedder that's already loaded, used instead of loading the model.
+    pub fn with_embedder(mut self, embedder: Box<dyn Embedder>) -> PhaticDetectorBuilder {
+        self.embedder = Some(embedder);
+        self
+    }
 
-    } else {
-        let embeddings = p.model.encode(&[text])?;
-        let mut embeddings = cluster::normalize_all_inplace(embeddings);
-        let embedding = take(&mut embeddings[0]);
+    /// Phatic sentences to compare text to, instead of the built in English chat examples.
+    pub fn with_examples(mut self, examples: Vec<String>) -> PhaticDetectorBuilder {
+        self.examples = Examples::Texts(examples);
+        self
+    }
 
-        Array::from_shape_vec((1, embedding.len()), embedding)?
-    };
+    /// Reads the examples from a file when building, one per line, skipping blank lines and
+    /// lines starting with `#`.
+    pub fn with_examples_file<P: Into<PathBuf>>(mut self, path: P) -> PhaticDetectorBuilder {
+        self.examples = Examples::File(path.into());
+        self
+    }
 
-    let scores = embedding_array.dot(&p.embeddings);
-    let count = scores.fold(0, |i, v| if *v > p.similarity { i + 1 } else { i });
-    Ok(count > 0)
-}
+    /// Sentences that aren't phatic, text closer to one of these than to every example isn't
+    /// phatic, however similar it is to an example.
+    pub fn with_negative_examples(mut self, examples: Vec<String>) -> PhaticDetectorBuilder {
+        self.negative_examples = Examples::Texts(examples);
+        self
+    }
 
-fn sanitise_text(text: &str) -> String {
-    // @todo is this correct? we're applying remove_user_mentions twice, and not removing other things...
-    let text = remove_user_mentions(text);
-    let text = remove_emoticons(&text);
-    remove_user_mentions(&text)
-}
+    /// Reads the negative examples from a file when building, like `with_examples_file`.
+    pub fn with_negative_examples_file<P: Into<PathBuf>>(mut self, path: P) -> PhaticDetectorBuilder {
+        self.negative_examples = Examples::File(path.into());
+        self
+    }
 
-fn clean_spaces(text: &str) -> String {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r" +")
-            .expect("To have valid regex");
+    /// Saves the example embeddings to a binary vectors file, and reads them back instead of
+    /// encoding the examples next time. The file is tagged with the model and a hash of the
+    /// examples, and rewritten when either changes.
+    pub fn with_embeddings_file<P: Into<PathBuf>>(mut self, path: P) -> PhaticDetectorBuilder {
+        self.embeddings_file = Some(path.into());
+        self
     }
-    RE.replace_all(text, " ").trim().to_owned()
-}
 
-// fn remove_new_lines(text: &str) -> String {
-//     todo!();
-// }
+    /// Text with fewer words than this is phatic without comparing it to the examples, 4 by
+    /// default, `None` compares even empty text.
+    pub fn with_min_words(mut self, min_words: Option<usize>) -> PhaticDetectorBuilder {
+        self.words.min = min_words;
+        self
+    }
 
-fn remove_user_mentions(text: &str) -> String {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"@[A-Za-z0-9]+")
-            .expect("To have valid regex");
+    /// Text with more words than this isn't phatic, without comparing it to the examples, 14 by
+    /// default, `None` compares text however long it is.
+    pub fn with_max_words(mut self, max_words: Option<usize>) -> PhaticDetectorBuilder {
+        self.words.max = max_words;
+        self
     }
-    clean_spaces(RE.replace_all(text, "").as_ref())
-}
 
-fn remove_emoticons(text: &str) -> String {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(
-            r"(:\w+:|<[/\\]?3|[\(\)\\\D|\*\$][\-\^]?[:;=]|[:;=B8][\-\^]?[3DOPp@\$\*\\\)\(/|])(\s|[!\.\?]|$)"
-        ).expect("To have valid regex");
+    /// How words are counted for `with_min_words` and `with_max_words`.
+    pub fn with_word_count(mut self, word_count: WordCount) -> PhaticDetectorBuilder {
+        self.words.count = word_count;
+        self
+    }
+
+    /// Loads the model and encodes the example sentences. The similarity threshold must be
+    /// between 0.001 and 0.999, there must be at least one example, the minimum word count can't
+    /// be above the maximum, and counting tokens needs a model with a tokenizer.
+    pub fn build(self) -> Result<PhaticDetector> {
+        let similarity = self.similarity_threshold;
+        if !(0.001..=0.999).contains(&similarity) {
+            return Err(Error::invalid_parameter("similarity", format!("{} is not in the range 0.001 <= similarity <= 0.999", similarity)));
+        }
+
+        if let (Some(min), Some(max)) = (self.words.min, self.words.max) {
+            if min > max {
+                return Err(Error::invalid_parameter("min-words", format!("{} is more than the maximum, {}", min, max)));
+            }
+        }
+
+        let examples = self.examples.load()?;
+        if examples.is_empty() {
+            return Err(Error::EmptyInput("phatic examples".to_string()));
+        }
+        let negatives = self.negative_examples.load()?;
+
+        let embedder = match self.embedder {
+            Some(embedder) => embedder,
+            None => self.model.load()?,
+        };
+        if self.words.count == WordCount::Tokenizer && embedder.count_tokens("")?.is_none() {
+            return Err(Error::invalid_parameter("word-count", format!("{} has no tokenizer to count tokens with", embedder.name())));
+        }
+
+        let all: Vec<&str> = examples.iter().chain(&negatives).map(String::as_str).collect();
+        let embeddings = match &self.embeddings_file {
+            Some(path) => load_or_encode_examples(embedder.as_ref(), &all, examples.len(), path)?,
+            None => encode_examples(embedder.as_ref(), &all)?,
+        };
+        Ok(PhaticDetector::new(similarity, self.words, embedder, examples, embeddings))
     }
-    clean_spaces(RE.replace_all(text, "").as_ref())
 }
 
-// fn remove_emoji(text: &str) -> String {
-//    todo!();
-// }
+fn vector_check(text: String, embedding: &Option<&Embedding>, p: &PhaticDetector) -> Result<PhaticVerdict>
+{
+    let embedding = if embedding.is_some() {
+        embedding.unwrap().clone()
+    } else {
+        let embeddings = p.embedder.encode(&[&text])?;
+        let mut embeddings = cluster::normalize_all_inplace(embeddings);
+        take(&mut embeddings[0])
+    };
+
+    let embedding_array = Array::from_shape_vec((1, embedding.len()), embedding).expect("one row");
+    Ok(p.compare(embedding_array.view(), vec![text])?.remove(0))
+}
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::embed::HashingEmbedder;
 
     #[test]
-    fn test_it_can_remove_duplicate_spaces() {
-        assert_eq!("", clean_spaces(""));
-        assert_eq!("", clean_spaces(" "));
-        assert_eq!("", clean_spaces("      "));
-        assert_eq!("hello", clean_spaces(" hello "));
-        assert_eq!("hello world", clean_spaces(" hello world  "));
-        assert_eq!("hello world", clean_spaces("hello  world"));
-        assert_eq!("hello world, foo bar", clean_spaces("hello  world, foo    bar  "));
+    fn test_it_checks_similarity_before_loading_model() {
+        for similarity in [0.0, 1.0, f32::NAN] {
+            let built = PhaticDetectorBuilder::new().with_similarity_threshold(similarity).build();
+            assert!(matches!(built, Err(Error::InvalidParameter { name: "similarity", .. })));
+        }
     }
 
     #[test]
-    fn test_it_can_remove_user_mentions() {
-        assert_eq!("", remove_user_mentions(""));
-        assert_eq!("", remove_user_mentions(" "));
-        assert_eq!("Hi there", remove_user_mentions("Hi there"));
-        assert_eq!("Hi there", remove_user_mentions("Hi there @tester"));
-        assert_eq!("Hi there test", remove_user_mentions("Hi there @tester test"));
-        assert_eq!("Hi there test", remove_user_mentions("Hi there @tester test @this"));
+    fn test_it_can_detect_phatic_sentences_without_a_model() {
+        let p = PhaticDetectorBuilder::new()
+            .with_similarity_threshold(0.9)
+            .with_embedder(Box::new(HashingEmbedder::new()))
+            .build()
+            .expect("To build detector instance");
+        let example = EXAMPLES.lines().find(|l| (4..15).contains(&l.split(' ').count())).expect("a mid length example");
+        assert!(p.is_phatic(example, &None).unwrap());
+        assert!(p.is_phatic("hi there", &None).unwrap());
+        assert!(!p.is_phatic("the invoice for march was charged twice to my card", &None).unwrap());
+    }
+
+    #[test]
+    fn test_it_can_use_custom_and_negative_examples() {
+        let build = |negatives: Vec<String>| {
+            PhaticDetectorBuilder::new()
+                .with_similarity_threshold(0.5)
+                .with_embedder(Box::new(HashingEmbedder::new()))
+                .with_examples(vec!["bonjour tout le monde".to_string(), "merci beaucoup pour votre aide".to_string()])
+                .with_negative_examples(negatives)
+                .build()
+                .expect("To build detector instance")
+        };
+        let text = "merci beaucoup pour votre commande";
+        assert!(build(vec![]).is_phatic(text, &None).unwrap());
+        assert!(!build(vec!["merci pour votre commande".to_string()]).is_phatic(text, &None).unwrap());
+        assert!(!build(vec![]).is_phatic("the invoice for march was charged twice", &None).unwrap());
+
+        let dir = std::env::temp_dir().join(format!("cluster-phatic-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("examples.txt"), "# french\n\nbonjour tout le monde\n").unwrap();
+        std::fs::write(dir.join("empty.txt"), "# nothing\n").unwrap();
+        let from_file = |name: &str| {
+            PhaticDetectorBuilder::new()
+                .with_embedder(Box::new(HashingEmbedder::new()))
+                .with_examples_file(dir.join(name))
+                .build()
+        };
+        assert!(from_file("examples.txt").unwrap().is_phatic("bonjour tout le monde ici", &None).unwrap());
+        assert!(matches!(from_file("empty.txt"), Err(Error::EmptyInput(_))));
+        assert!(matches!(from_file("missing.txt"), Err(Error::Io { .. })));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_it_classifies_in_batches() {
+        let p = PhaticDetectorBuilder::new()
+            .with_similarity_threshold(0.5)
+            .with_embedder(Box::new(HashingEmbedder::new()))
+            .with_examples(vec!["thanks a lot for your help".to_string(), "good morning to you all".to_string()])
+            .build()
+            .expect("To build detector instance");
+        let texts: Vec<String> = [
+            "hi @bob",
+            "thanks a lot for your help today",
+            "the invoice for march was charged twice",
+            "good morning to you all :)",
+            "one two three four five six seven eight nine ten eleven twelve thirteen fourteen fifteen",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let verdicts = p.classify(&texts, &EncodeOptions::new().with_batch_size(2)).unwrap();
+        assert_eq!(vec![true, true, false, true, false], verdicts.iter().map(|v| v.phatic).collect::<Vec<_>>());
+        for (text, verdict) in texts.iter().zip(&verdicts) {
+            assert_eq!(p.is_phatic(text, &None).unwrap(), verdict.phatic);
+        }
+        let embeddings = HashingEmbedder::new().encode(&texts.iter().map(String::as_str).collect::<Vec<&str>>()).unwrap();
+        let embeddings = cluster::vectors_to_array(cluster::normalize_all_inplace(embeddings)).unwrap();
+        assert_eq!(verdicts, p.classify_embedded(&texts, embeddings.view()).unwrap());
+        assert!(p.classify_embedded(&texts[1..], embeddings.view()).is_err());
+        assert_eq!((None, None), (verdicts[0].similarity, verdicts[0].nearest_example));
+        assert_eq!(Some(0), verdicts[1].nearest_example);
+        assert_eq!(Some("good morning to you all"), verdicts[3].nearest_example_text.as_deref());
+        assert!((verdicts[3].similarity.unwrap() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_it_explains_verdicts() {
+        let p = PhaticDetectorBuilder::new()
+            .with_similarity_threshold(0.5)
+            .with_embedder(Box::new(HashingEmbedder::new()))
+            .with_examples(vec!["thanks a lot for your help".to_string()])
+            .with_negative_examples(vec!["thanks a lot for the refund".to_string()])
+            .build()
+            .expect("To build detector instance");
+        let rule = |text: &str| p.analyse(text, &None).unwrap().rule;
+
+        let verdict = p.analyse("hi there @bob :)", &None).unwrap();
+        assert_eq!((true, PhaticRule::FewWords, "hi there"), (verdict.phatic, verdict.rule, verdict.text.as_str()));
+        assert_eq!(None, verdict.similarity);
+        assert_eq!(PhaticRule::ManyWords, rule("one two three four five six seven eight nine ten eleven twelve thirteen fourteen fifteen"));
+        assert_eq!(PhaticRule::NotSimilar, rule("the invoice for march was charged twice"));
+        assert_eq!(PhaticRule::NegativeExample, rule("thanks a lot for the refund"));
+
+        let verdict = p.analyse("thanks a lot for your help @bob", &None).unwrap();
+        assert_eq!((true, PhaticRule::Similar), (verdict.phatic, verdict.rule));
+        assert_eq!(Some(0), verdict.nearest_example);
+        assert_eq!(Some("thanks a lot for your help"), verdict.nearest_example_text.as_deref());
+        assert_eq!("thanks a lot for your help", verdict.text);
+        assert!(verdict.negative_similarity.unwrap() < verdict.similarity.unwrap());
+    }
+
+    #[test]
+    fn test_word_limits_at_the_boundaries() {
+        let build = |builder: PhaticDetectorBuilder| {
+            builder
+                .with_similarity_threshold(0.999)
+                .with_embedder(Box::new(HashingEmbedder::new()))
+                .with_examples(vec!["nothing like any of these".to_string()])
+                .build()
+                .expect("To build detector instance")
+        };
+        let words = |count: usize| (0..count).map(|i| format!("word{}", i)).collect::<Vec<_>>().join(" ");
+        let rules = |p: &PhaticDetector| [3, 4, 14, 15].map(|count| p.analyse(&words(count), &None).unwrap().rule);
+
+        let p = build(PhaticDetectorBuilder::new());
+        assert_eq!([PhaticRule::FewWords, PhaticRule::NotSimilar, PhaticRule::NotSimilar, PhaticRule::ManyWords], rules(&p));
+        assert_eq!([true, false, false, false], [3, 4, 14, 15].map(|count| p.is_phatic(&words(count), &None).unwrap()));
+
+        let p = build(PhaticDetectorBuilder::new().with_min_words(Some(5)).with_max_words(Some(13)));
+        assert_eq!([PhaticRule::FewWords, PhaticRule::FewWords, PhaticRule::ManyWords, PhaticRule::ManyWords], rules(&p));
+
+        let p = build(PhaticDetectorBuilder::new().with_min_words(None).with_max_words(None));
+        assert_eq!([PhaticRule::NotSimilar; 4], rules(&p));
+        let verdict = p.analyse("", &None).unwrap();
+        assert_eq!(PhaticRule::NotSimilar, verdict.rule);
+        assert!(verdict.similarity.unwrap().abs() <= 1.0);
+
+        // "don't" is one word to whitespace, two to the hashing model
+        let p = build(PhaticDetectorBuilder::new().with_word_count(WordCount::Tokenizer));
+        assert_eq!(PhaticRule::FewWords, p.analyse("please don't", &None).unwrap().rule);
+        assert_eq!(PhaticRule::NotSimilar, p.analyse("please don't go", &None).unwrap().rule);
+
+        let built = PhaticDetectorBuilder::new().with_min_words(Some(10)).with_max_words(Some(9)).build();
+        assert!(matches!(built, Err(Error::InvalidParameter { name: "min-words", .. })));
     }
 
     #[test]
-    fn test_it_can_remove_emoticons() {
-        assert_eq!("", remove_emoticons(""));
-        assert_eq!("", remove_emoticons(" "));
-        assert_eq!("", remove_emoticons(":) "));
-        assert_eq!("Hi", remove_emoticons("Hi :) "));
-        assert_eq!("Hi", remove_emoticons("Hi :) "));
-        assert_eq!("Hi", remove_emoticons("Hi :) :( :-( :D :p"));
-        assert_eq!("Hi john", remove_emoticons("Hi :) john"));
+    fn test_it_saves_example_embeddings() {
+        let dir = std::env::temp_dir().join(format!("cluster-phatic-embeddings-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("examples.bin");
+        let build = |examples: &[&str], dimension: usize| {
+            PhaticDetectorBuilder::new()
+                .with_embedder(Box::new(HashingEmbedder::new().with_dimension(dimension)))
+                .with_examples(examples.iter().map(|s| s.to_string()).collect())
+                .with_embeddings_file(&path)
+                .build()
+                .unwrap()
+        };
+        let tag = || VectorFile::open(&path.display().to_string()).unwrap().header().model.clone();
+
+        let built = build(&["hello there", "thanks a lot"], 64);
+        let first = tag();
+        assert!(first.starts_with("hashing-64 phatic-examples-sha256:"));
+        assert_eq!(built.embeddings, build(&["hello there", "thanks a lot"], 64).embeddings);
+        assert_eq!(first, tag());
+
+        // what's in the file is used rather than encoding again, kept as one column per example
+        let saved = Array2::from_elem((2, 64), 0.125);
+        binary::write(&path.display().to_string(), saved.view(), &first).unwrap();
+        assert_eq!(saved.t(), build(&["hello there", "thanks a lot"], 64).embeddings);
+
+        build(&["hello there"], 64);
+        assert_ne!(first, tag());
+        let built = build(&["hello there"], 32);
+        assert_eq!(32, built.embeddings.nrows());
+        assert!(tag().starts_with("hashing-32 "));
+
+        assert_ne!(examples_tag("m", &["a", "b"], 1), examples_tag("m", &["a", "b"], 2));
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
+    #[cfg(feature = "rust-bert")]
     fn test_it_can_detect_phatic_sentences() {
         let p = PhaticDetectorBuilder::new()
             .with_similarity_threshold(0.5)
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::cluster::Index;
+use crate::documents::Documents;
+use crate::report::ClusterReport;
+
+/// Things `Preprocessor` can take out of text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Strip {
+    /// `@name`
+    Mentions,
+    /// `:)`, `:-(`, `<3`, `:smile:` and the like
+    Emoticons,
+    /// `http://`, `https://` and `www.` links
+    Urls,
+    Emails,
+    /// Runs of whitespace, including tabs, become one space, and ends are trimmed
+    Whitespace,
+}
+
+impl Strip {
+    pub const NAMES: [&'static str; 5] = ["mentions", "emoticons", "urls", "emails", "whitespace"];
+
+    /// Emails and urls go first, a mention would take the domain out of an email.
+    const ORDER: [Strip; 5] = [Strip::Urls, Strip::Emails, Strip::Mentions, Strip::Emoticons, Strip::Whitespace];
+}
+
+impl FromStr for Strip {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Strip, String> {
+        match s {
+            "mentions" => Ok(Strip::Mentions),
+            "emoticons" => Ok(Strip::Emoticons),
+            "urls" => Ok(Strip::Urls),
+            "emails" => Ok(Strip::Emails),
+            "whitespace" => Ok(Strip::Whitespace),
+            _ => Err(format!("unknown strip {}, expected one of {}", s, Strip::NAMES.join(", "))),
+        }
+    }
+}
+
+/// Cleans documents up before they're embedded, and leaves out the ones not worth embedding.
+/// Does nothing until configured, so every document is a row of its own.
+///
+/// Text is stripped, then lowercased, then counted in whitespace separated tokens. Documents
+/// with too few or too many tokens are dropped, then exact duplicates are collapsed into the
+/// first of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Preprocessor {
+    strip: Vec<Strip>,
+    lowercase: bool,
+    dedup: bool,
+    min_tokens: usize,
+    max_tokens: Option<usize>,
+}
+
+impl Preprocessor {
+    pub fn new() -> Preprocessor {
+        Preprocessor::default()
+    }
+
+    pub fn with_strip(mut self, strip: Strip) -> Preprocessor {
+        if !self.strip.contains(&strip) {
+            self.strip.push(strip);
+        }
+        self
+    }
+
+    pub fn with_lowercase(mut self, lowercase: bool) -> Preprocessor {
+        self.lowercase = lowercase;
+        self
+    }
+
+    /// Embeds each distinct text once, see `Preprocessed::lines` for where they all were.
+    pub fn with_dedup(mut self, dedup: bool) -> Preprocessor {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Drops documents with fewer tokens, 1 drops the empty ones.
+    pub fn with_min_tokens(mut self, min_tokens: usize) -> Preprocessor {
+        self.min_tokens = min_tokens;
+        self
+    }
+
+    /// Drops documents with more tokens.
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Preprocessor {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Cleans one text.
+    pub fn clean(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for strip in Strip::ORDER.iter().filter(|strip| self.strip.contains(strip)) {
+            text = match strip {
+                Strip::Mentions => remove_user_mentions(&text),
+                Strip::Emoticons => remove_emoticons(&text),
+                Strip::Urls => remove_urls(&text),
+                Strip::Emails => remove_emails(&text),
+                Strip::Whitespace => text.split_whitespace().collect::<Vec<&str>>().join(" "),
+            };
+        }
+        if self.lowercase {
+            text = text.to_lowercase();
+        }
+        text
+    }
+
+    pub fn apply(&self, texts: &[String]) -> Preprocessed {
+        let mut preprocessed = Preprocessed::default();
+        let mut rows: HashMap<String, usize> = HashMap::new();
+
+        for (line, text) in texts.iter().enumerate() {
+            let text = self.clean(text);
+            let tokens = text.split_whitespace().count();
+            if tokens < self.min_tokens || self.max_tokens.is_some_and(|max_tokens| tokens > max_tokens) {
+                preprocessed.dropped.push(line);
+                continue;
+            }
+            if self.dedup {
+                if let Some(row) = rows.get(&text) {
+                    preprocessed.lines[*row].push(line);
+                    continue;
+                }
+                rows.insert(text.clone(), preprocessed.texts.len());
+            }
+            preprocessed.texts.push(text);
+            preprocessed.lines.push(vec![line]);
+        }
+        preprocessed
+    }
+}
+
+/// Documents after preprocessing. Row `i` of embeddings made from `texts` is every document in
+/// `lines[i]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Preprocessed {
+    pub texts: Vec<String>,
+    /// The documents, counting from 0, each text came from, in input order, the first of them
+    /// is where it first appeared. Lines of a text file, rows of a CSV file.
+    pub lines: Vec<Vec<Index>>,
+    /// Documents left out for having too few or too many tokens
+    pub dropped: Vec<Index>,
+}
+
+impl Preprocessed {
+    pub fn len(&self) -> usize {
+        self.texts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.texts.is_empty()
+    }
+
+    /// Documents left out as duplicates of an earlier one.
+    pub fn duplicates(&self) -> usize {
+        self.lines.iter().map(|lines| lines.len() - 1).sum()
+    }
+
+    /// Turns reports on clusters of rows into reports on the documents they came from, with the
+    /// original text and id of each document, and the duplicates of each member.
+    pub fn trace_reports(&self, reports: Vec<ClusterReport>, documents: &Documents) -> Vec<ClusterReport> {
+        let first = |row: Index| self.lines[row][0];
+        reports
+            .into_iter()
+            .map(|mut report| {
+                report.centroid = first(report.centroid);
+                report.centroid_id = documents.id(report.centroid).map(str::to_string);
+                report.centroid_text = Some(documents.texts[report.centroid].clone());
+                for member in &mut report.members {
+                    member.duplicates = self.lines[member.index][1..].to_vec();
+                    member.index = first(member.index);
+                    member.id = documents.id(member.index).map(str::to_string);
+                    member.text = Some(documents.texts[member.index].clone());
+                }
+                report
+            })
+            .collect()
+    }
+}
+
+/// What phatic detection compares, text without mentions or emoticons.
+pub fn sanitise_text(text: &str) -> String {
+    // @todo is this correct? we're applying remove_user_mentions twice, and not removing other things...
+    let text = remove_user_mentions(text);
+    let text = remove_emoticons(&text);
+    remove_user_mentions(&text)
+}
+
+pub fn clean_spaces(text: &str) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r" +")
+            .expect("To have valid regex");
+    }
+    RE.replace_all(text, " ").trim().to_owned()
+}
+
+// fn remove_new_lines(text: &str) -> String {
+//     todo!();
+// }
+
+pub fn remove_user_mentions(text: &str) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"@[A-Za-z0-9]+")
+            .expect("To have valid regex");
+    }
+    clean_spaces(RE.replace_all(text, "").as_ref())
+}
+
+pub fn remove_emoticons(text: &str) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(
+            r"(:\w+:|<[/\\]?3|[\(\)\\\D|\*\$][\-\^]?[:;=]|[:;=B8][\-\^]?[3DOPp@\$\*\\\)\(/|])(\s|[!\.\?]|$)"
+        ).expect("To have valid regex");
+    }
+    clean_spaces(RE.replace_all(text, "").as_ref())
+}
+
+// fn remove_emoji(text: &str) -> String {
+//    todo!();
+// }
+
+pub fn remove_urls(text: &str) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"(?i)\b(?:https?://|www\.)\S+")
+            .expect("To have valid regex");
+    }
+    clean_spaces(RE.replace_all(text, "").as_ref())
+}
+
+pub fn remove_emails(text: &str) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"[A-Za-z0-9._%+\-]+@[A-Za-z0-9.\-]+\.[A-Za-z]{2,}")
+            .expect("To have valid regex");
+    }
+    clean_spaces(RE.replace_all(text, "").as_ref())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::Member;
+
+    #[test]
+    fn test_it_can_remove_duplicate_spaces() {
+        assert_eq!("", clean_spaces(""));
+        assert_eq!("", clean_spaces(" "));
+        assert_eq!("", clean_spaces("      "));
+        assert_eq!("hello", clean_spaces(" hello "));
+        assert_eq!("hello world", clean_spaces(" hello world  "));
+        assert_eq!("hello world", clean_spaces("hello  world"));
+        assert_eq!("hello world, foo bar", clean_spaces("hello  world, foo    bar  "));
+    }
+
+    #[test]
+    fn test_it_can_remove_user_mentions() {
+        assert_eq!("", remove_user_mentions(""));
+        assert_eq!("", remove_user_mentions(" "));
+        assert_eq!("Hi there", remove_user_mentions("Hi there"));
+        assert_eq!("Hi there", remove_user_mentions("Hi there @tester"));
+        assert_eq!("Hi there test", remove_user_mentions("Hi there @tester test"));
+        assert_eq!("Hi there test", remove_user_mentions("Hi there @tester test @this"));
+    }
+
+    #[test]
+    fn test_it_can_remove_emoticons() {
+        assert_eq!("", remove_emoticons(""));
+        assert_eq!("", remove_emoticons(" "));
+        assert_eq!("", remove_emoticons(":) "));
+        assert_eq!("Hi", remove_emoticons("Hi :) "));
+        assert_eq!("Hi", remove_emoticons("Hi :) "));
+        assert_eq!("Hi", remove_emoticons("Hi :) :( :-( :D :p"));
+        assert_eq!("Hi john", remove_emoticons("Hi :) john"));
+    }
+
+    #[test]
+    fn test_it_can_remove_urls_and_emails() {
+        assert_eq!("see", remove_urls("see https://example.com/a?b=c"));
+        assert_eq!("see or", remove_urls("see www.example.com or HTTP://x.io"));
+        assert_eq!("mail or", remove_emails("mail bob.smith+x@example.co.uk or"));
+        assert_eq!("bob@localhost", remove_emails("bob@localhost"));
+
+        let preprocessor = Preprocessor::new().with_strip(Strip::Mentions).with_strip(Strip::Emails);
+        assert_eq!("write to", preprocessor.clean("write to bob@example.com"));
+    }
+
+    #[test]
+    fn test_it_keeps_every_document_by_default() {
+        let texts: Vec<String> = ["a", "", "a"].iter().map(|s| s.to_string()).collect();
+        let preprocessed = Preprocessor::new().apply(&texts);
+        assert_eq!(texts, preprocessed.texts);
+        assert_eq!(vec![vec![0], vec![1], vec![2]], preprocessed.lines);
+    }
+
+    #[test]
+    fn test_it_collapses_duplicates_and_filters_tokens() {
+        let texts: Vec<String> = [
+            "Reset my  password @support",
+            "",
+            "reset my password",
+            "see https://example.com",
+            "one two three four five six",
+            "RESET MY PASSWORD :)",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let preprocessor = Preprocessor::new()
+            .with_strip(Strip::Mentions)
+            .with_strip(Strip::Emoticons)
+            .with_strip(Strip::Urls)
+            .with_strip(Strip::Whitespace)
+            .with_lowercase(true)
+            .with_dedup(true)
+            .with_min_tokens(1)
+            .with_max_tokens(5);
+        let preprocessed = preprocessor.apply(&texts);
+
+        assert_eq!(vec!["reset my password", "see"], preprocessed.texts);
+        assert_eq!(vec![vec![0, 2, 5], vec![3]], preprocessed.lines);
+        assert_eq!(vec![1, 4], preprocessed.dropped);
+        assert_eq!(2, preprocessed.duplicates());
+
+        let documents = Documents { texts, ids: None };
+        let reports = vec![ClusterReport {
+            centroid: 1,
+            centroid_id: None,
+            centroid_text: None,
+            size: 2,
+            members: [1, 0].iter().map(|index| Member { index: *index, id: None, text: None, similarity: 1.0, duplicates: vec![] }).collect(),
+        }];
+        let reports = preprocessed.trace_reports(reports, &documents);
+        assert_eq!(3, reports[0].centroid);
+        assert_eq!(Some("see https://example.com".to_string()), reports[0].centroid_text);
+        assert_eq!((0, vec![2, 5]), (reports[0].members[1].index, reports[0].members[1].duplicates.clone()));
+        assert_eq!(Some("Reset my  password @support".to_string()), reports[0].members[1].text);
+    }
+}
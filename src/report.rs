@@ -0,0 +1,112 @@
+use ndarray::ArrayView2;
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::{self, Clusters, Index};
+use crate::documents::Documents;
+use crate::error::{Error, Result};
+
+/// A document in a cluster, and how close it is to the centroid.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Member {
+    pub index: Index,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub text: Option<String>,
+    /// Cosine similarity to the centroid, 1 for the centroid itself
+    pub similarity: f32,
+    /// Later documents with the same text, when duplicates were collapsed by preprocessing
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub duplicates: Vec<Index>,
+}
+
+/// A cluster ready to be read by a person, rather than joined back against the input by hand.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterReport {
+    pub centroid: Index,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub centroid_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub centroid_text: Option<String>,
+    pub size: usize,
+    /// Most similar to the centroid first
+    pub members: Vec<Member>,
+}
+
+/// Attaches similarities to the centroid, and the text and id of each document if there are
+/// any, to clusters made from `embeddings`. The embeddings must be normalized, and there must be
+/// a document per embedding.
+pub fn describe_clusters(clusters: &Clusters, embeddings: ArrayView2<f32>, documents: Option<&Documents>) -> Result<Vec<ClusterReport>> {
+    cluster::check_clusters(clusters, embeddings.nrows())?;
+    if let Some(documents) = documents {
+        if documents.len() != embeddings.nrows() {
+            return Err(Error::invalid_parameter(
+                "input-text",
+                format!("{} documents for {} vectors, expected one document per vector", documents.len(), embeddings.nrows()),
+            ));
+        }
+    }
+    let text = |index: Index| documents.map(|documents| documents.texts[index].clone());
+    let id = |index: Index| documents.and_then(|documents| documents.id(index)).map(str::to_string);
+
+    let reports = clusters
+        .iter()
+        .map(|(centroid, members)| {
+            let centroid_embedding = embeddings.row(*centroid);
+            let mut members: Vec<Member> = members
+                .iter()
+                .map(|index| Member {
+                    index: *index,
+                    id: id(*index),
+                    text: text(*index),
+                    similarity: centroid_embedding.dot(&embeddings.row(*index)),
+                    duplicates: vec![],
+                })
+                .collect();
+            members.sort_by(|a, b| b.similarity.total_cmp(&a.similarity).then(a.index.cmp(&b.index)));
+
+            ClusterReport {
+                centroid: *centroid,
+                centroid_id: id(*centroid),
+                centroid_text: text(*centroid),
+                size: members.len(),
+                members,
+            }
+        })
+        .collect();
+    Ok(reports)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_it_describes_clusters() {
+        let embeddings = array![[1.0, 0.0], [0.6, 0.8], [0.8, 0.6], [0.0, 1.0]];
+        let documents = Documents {
+            texts: ["a", "b", "c", "d"].iter().map(|s| s.to_string()).collect(),
+            ids: Some(["T-1", "T-2", "T-3", "T-4"].iter().map(|s| s.to_string()).collect()),
+        };
+        let clusters = vec![(0, vec![0, 1, 2])];
+
+        let reports = describe_clusters(&clusters, embeddings.view(), Some(&documents)).unwrap();
+        assert_eq!(1, reports.len());
+        assert_eq!(Some("a".to_string()), reports[0].centroid_text);
+        assert_eq!(Some("T-1".to_string()), reports[0].centroid_id);
+        assert_eq!(3, reports[0].size);
+        assert_eq!(vec![0, 2, 1], reports[0].members.iter().map(|m| m.index).collect::<Vec<_>>());
+        assert_eq!(Some("c".to_string()), reports[0].members[1].text);
+        assert_eq!(Some("T-3".to_string()), reports[0].members[1].id);
+        assert!((reports[0].members[1].similarity - 0.8).abs() < 1e-6);
+
+        let json = serde_json::to_string(&describe_clusters(&clusters, embeddings.view(), None).unwrap()).unwrap();
+        assert!(json.starts_with(r#"[{"centroid":0,"size":3,"members":[{"index":0,"similarity":1.0}"#));
+
+        let documents = Documents { texts: documents.texts[..3].to_vec(), ids: None };
+        assert!(describe_clusters(&clusters, embeddings.view(), Some(&documents)).is_err());
+        assert!(describe_clusters(&vec![(4, vec![4])], embeddings.view(), None).is_err());
+    }
+}
@@ -1,6 +1,9 @@
 use chrono::{DateTime, Local};
+use std::io::{IsTerminal, Write};
 use std::ops::Sub;
+use std::time::{Duration, Instant};
 
+/// Prints wall clock time taken between `start` and `end`, see `time_it!`.
 pub struct Timer {
     name: &'static str,
     start: DateTime<Local>,
@@ -29,19 +32,81 @@ impl Timer {
 
 /// Usage:
 /// ```
+/// # use cluster::time_it;
 /// time_it!("some timer name",
 ///   let x = 10;
-///   println!("{}", x)
-/// )
+///   println!("{}", x);
+/// );
 /// ```
 ///
 #[macro_export]
 macro_rules! time_it {
     ($context:literal, $($tt:tt)+) => {
-        let timer = crate::timer::Timer::start($context);
+        let timer = $crate::timer::Timer::start($context);
         $(
             $tt
         )+
         timer.end();
     }
 }
+
+/// Reports how far a long job has got on stderr, with the rate and the time left. Rewrites one
+/// line on a terminal, otherwise prints a line every few seconds so logs don't fill up.
+pub struct Progress {
+    name: &'static str,
+    total: usize,
+    done: usize,
+    start: Instant,
+    last_report: Option<Instant>,
+    terminal: bool,
+}
+
+impl Progress {
+    pub fn start(name: &'static str, total: usize) -> Progress {
+        Progress { name, total, done: 0, start: Instant::now(), last_report: None, terminal: std::io::stderr().is_terminal() }
+    }
+
+    /// Counts `rows` more as done, reporting if it's been long enough since the last report.
+    pub fn add(&mut self, rows: usize) {
+        self.done += rows;
+        let every = if self.terminal { Duration::from_millis(500) } else { Duration::from_secs(10) };
+        if self.last_report.is_none_or(|last| last.elapsed() >= every) {
+            self.report();
+        }
+    }
+
+    pub fn finish(mut self) {
+        self.report();
+        if self.terminal {
+            eprintln!();
+        }
+    }
+
+    fn report(&mut self) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { self.done as f64 / elapsed } else { 0.0 };
+        let eta = if rate > 0.0 {
+            format_duration((self.total - self.done.min(self.total)) as f64 / rate)
+        } else {
+            "?".to_string()
+        };
+        let line = format!("{}: {}/{} rows, {:.0} rows/s, ETA {}", self.name, self.done, self.total, rate, eta);
+        if self.terminal {
+            eprint!("\r{}\x1b[K", line);
+            let _ = std::io::stderr().flush();
+        } else {
+            eprintln!("{}", line);
+        }
+        self.last_report = Some(Instant::now());
+    }
+}
+
+/// Whole seconds, as `1h02m03s`, `2m03s` or `3s`.
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
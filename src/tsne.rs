@@ -1,22 +1,27 @@
 use bhtsne::tSNE;
-use ndarray::{Array2, s};
+use ndarray::{ArrayView2, s};
 
-use crate::cluster::Clusters;
+use crate::cluster::{self, Clusters};
+use crate::error::Result;
 
-pub fn reduce_dimensions(clusters: &Clusters, embeddings: &Array2<f32>) -> Vec<(f32, f32)>
+/// Reduces the embedding of each cluster centroid to a 2d point, for plotting. The clusters
+/// must have been made from `embeddings`.
+pub fn reduce_dimensions(clusters: &Clusters, embeddings: ArrayView2<f32>) -> Result<Vec<(f32, f32)>>
 {
+    cluster::check_clusters(clusters, embeddings.nrows())?;
+
     let mut v: Vec<(f32, f32)> = Vec::new();
     if clusters.len() == 0 {
-        return v;
+        return Ok(v);
     }
     if clusters.len() == 1 {
         v.push((0.0, 0.0));
-        return v;
+        return Ok(v);
     }
     if clusters.len() == 2 {
         v.push((100.0, 100.0));
         v.push((-100.0, -100.0));
-        return v;
+        return Ok(v);
     }
 
     /*
@@ -94,5 +99,5 @@ pub fn reduce_dimensions(clusters: &Clusters, embeddings: &Array2<f32>) -> Vec<(
 
     */
 
-    points.iter().map(|t| (t.0, t.1)).collect()
+    Ok(points.iter().map(|t| (t.0, t.1)).collect())
 }
\ No newline at end of file